@@ -0,0 +1,198 @@
+//! Stand-in for the cycles minting and exchange rate canisters of the NNS in the e2e tests.
+//!
+//! Both quote a fixed rate of 4 trillion cycles per ICP. Top-ups are read from the ledger, the
+//! same way the cycles minting canister does, and their cycles are deposited to the canister.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use candid::CandidType;
+use candid::Principal;
+use ic_cdk::api::management_canister::main::deposit_cycles;
+use ic_cdk::api::management_canister::main::CanisterIdRecord;
+use ic_ledger_types::query_blocks;
+use ic_ledger_types::AccountIdentifier;
+use ic_ledger_types::BlockIndex;
+use ic_ledger_types::GetBlocksArgs;
+use ic_ledger_types::Memo;
+use ic_ledger_types::Operation;
+use ic_ledger_types::Subaccount;
+use ic_ledger_types::MAINNET_LEDGER_CANISTER_ID;
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+
+// 1 XDR is converted into 1 trillion cycles, 1 ICP is worth 4 XDR.
+const XDR_PERMYRIAD_PER_ICP: u64 = 40_000;
+const CYCLES_PER_ICP: u64 = 4_000_000_000_000;
+const CYCLES_PER_E8: u128 = CYCLES_PER_ICP as u128 / 100_000_000;
+const MEMO_TOP_UP_CANISTER: Memo = Memo(1347768404);
+
+thread_local! {
+    // Cycles minted for each notified block, so a block is only minted once.
+    static MINTED: RefCell<BTreeMap<BlockIndex, u128>> = RefCell::default();
+}
+
+#[derive(CandidType)]
+struct IcpXdrConversionRate {
+    timestamp_seconds: u64,
+    xdr_permyriad_per_icp: u64,
+}
+
+#[derive(CandidType)]
+struct IcpXdrConversionRateResponse {
+    data: IcpXdrConversionRate,
+    hash_tree: ByteBuf,
+    certificate: ByteBuf,
+}
+
+#[ic_cdk::query]
+fn get_icp_xdr_conversion_rate() -> IcpXdrConversionRateResponse {
+    IcpXdrConversionRateResponse {
+        data: IcpXdrConversionRate {
+            timestamp_seconds: ic_cdk::api::time() / 1_000_000_000,
+            xdr_permyriad_per_icp: XDR_PERMYRIAD_PER_ICP,
+        },
+        hash_tree: ByteBuf::new(),
+        certificate: ByteBuf::new(),
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+struct NotifyTopUpArg {
+    block_index: BlockIndex,
+    canister_id: Principal,
+}
+
+#[derive(CandidType)]
+enum NotifyError {
+    InvalidTransaction(String),
+    Other {
+        error_code: u64,
+        error_message: String,
+    },
+}
+
+#[ic_cdk::update]
+async fn notify_top_up(arg: NotifyTopUpArg) -> Result<u128, NotifyError> {
+    let NotifyTopUpArg {
+        block_index,
+        canister_id,
+    } = arg;
+    if let Some(cycles) = MINTED.with_borrow(|m| m.get(&block_index).copied()) {
+        return Ok(cycles);
+    }
+
+    let other = |(code, message): (_, String)| NotifyError::Other {
+        error_code: code as u64,
+        error_message: message,
+    };
+    let response = query_blocks(
+        MAINNET_LEDGER_CANISTER_ID,
+        GetBlocksArgs {
+            start: block_index,
+            length: 1,
+        },
+    )
+    .await
+    .map_err(other)?;
+    let Some(block) = response.blocks.into_iter().next() else {
+        return Err(NotifyError::InvalidTransaction(
+            "Block not found".to_string(),
+        ));
+    };
+
+    let top_up_account = AccountIdentifier::new(&ic_cdk::id(), &Subaccount::from(canister_id));
+    let cycles = match block.transaction.operation {
+        Some(Operation::Transfer { to, amount, .. })
+            if to == top_up_account && block.transaction.memo == MEMO_TOP_UP_CANISTER =>
+        {
+            amount.e8s() as u128 * CYCLES_PER_E8
+        }
+        _ => {
+            return Err(NotifyError::InvalidTransaction(
+                "Not a top-up of the canister".to_string(),
+            ))
+        }
+    };
+
+    // Recorded before depositing, so a concurrent notification does not mint the block again.
+    if MINTED.with_borrow_mut(|m| m.insert(block_index, cycles).is_some()) {
+        return Ok(cycles);
+    }
+    if let Err(e) = deposit_cycles(CanisterIdRecord { canister_id }, cycles).await {
+        MINTED.with_borrow_mut(|m| m.remove(&block_index));
+        return Err(other(e));
+    }
+    Ok(cycles)
+}
+
+#[derive(CandidType, Deserialize)]
+enum AssetClass {
+    Cryptocurrency,
+    FiatCurrency,
+}
+
+#[derive(CandidType, Deserialize)]
+struct Asset {
+    symbol: String,
+    class: AssetClass,
+}
+
+#[derive(CandidType, Deserialize)]
+struct GetExchangeRateRequest {
+    base_asset: Asset,
+    quote_asset: Asset,
+    timestamp: Option<u64>,
+}
+
+#[derive(CandidType)]
+struct ExchangeRateMetadata {
+    decimals: u32,
+    base_asset_num_received_rates: u64,
+    base_asset_num_queried_sources: u64,
+    quote_asset_num_received_rates: u64,
+    quote_asset_num_queried_sources: u64,
+    standard_deviation: u64,
+    forex_timestamp: Option<u64>,
+}
+
+#[derive(CandidType)]
+struct ExchangeRate {
+    base_asset: Asset,
+    quote_asset: Asset,
+    timestamp: u64,
+    rate: u64,
+    metadata: ExchangeRateMetadata,
+}
+
+#[derive(CandidType)]
+enum ExchangeRateError {
+    CryptoBaseAssetNotFound,
+}
+
+#[ic_cdk::update]
+fn get_exchange_rate(request: GetExchangeRateRequest) -> Result<ExchangeRate, ExchangeRateError> {
+    if request.base_asset.symbol != "ICP" || request.quote_asset.symbol != "Cycles" {
+        return Err(ExchangeRateError::CryptoBaseAssetNotFound);
+    }
+    // The exchange rate canister charges for its rates.
+    ic_cdk::api::call::msg_cycles_accept128(ic_cdk::api::call::msg_cycles_available128());
+
+    Ok(ExchangeRate {
+        base_asset: request.base_asset,
+        quote_asset: request.quote_asset,
+        timestamp: request
+            .timestamp
+            .unwrap_or(ic_cdk::api::time() / 1_000_000_000),
+        rate: CYCLES_PER_ICP,
+        metadata: ExchangeRateMetadata {
+            decimals: 0,
+            base_asset_num_received_rates: 1,
+            base_asset_num_queried_sources: 1,
+            quote_asset_num_received_rates: 1,
+            quote_asset_num_queried_sources: 1,
+            standard_deviation: 0,
+            forex_timestamp: None,
+        },
+    })
+}
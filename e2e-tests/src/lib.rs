@@ -1,3 +1,4 @@
+#[allow(clippy::large_enum_variant)]
 pub mod declarations;
 pub mod setup;
 pub mod utils;
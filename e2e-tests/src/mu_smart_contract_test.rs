@@ -1,22 +1,72 @@
 use crate::declarations::mu_smart_contract;
+use crate::declarations::mu_smart_contract::Account;
+use crate::declarations::mu_smart_contract::AdminResult;
 use crate::declarations::mu_smart_contract::Error;
+use crate::declarations::mu_smart_contract::FundEscrowResult;
 use crate::declarations::mu_smart_contract::GetDeveloperResult;
+use crate::declarations::mu_smart_contract::GetPendingTopUpsResult;
+use crate::declarations::mu_smart_contract::GetSettingsResult;
+use crate::declarations::mu_smart_contract::IcpCyclesRate;
+use crate::declarations::mu_smart_contract::RequestEscrowWithdrawIcrc1Result;
 use crate::declarations::mu_smart_contract::RequestEscrowWithdrawResult;
 use crate::declarations::mu_smart_contract::Result_;
 use crate::setup::TestCase;
+use crate::utils::app_package;
+use crate::utils::empty_settings_update;
+use crate::utils::random_principal;
+use crate::utils::test_wasm_module;
+use crate::utils::trapping_init_wasm_module;
 
+use crate::declarations::mu_smart_contract::AppBudget;
+use crate::declarations::mu_smart_contract::AppData;
 use crate::declarations::mu_smart_contract::AppDto;
 use crate::declarations::mu_smart_contract::AppState;
+use crate::declarations::mu_smart_contract::AppUsagesPage;
+use crate::declarations::mu_smart_contract::AppUsagesRequest;
+use crate::declarations::mu_smart_contract::AppsPage;
+use crate::declarations::mu_smart_contract::BeginUploadResult;
+use crate::declarations::mu_smart_contract::ClearNotificationsResult;
+use crate::declarations::mu_smart_contract::CyclesPolicy;
 use crate::declarations::mu_smart_contract::DeployAppRequest;
 use crate::declarations::mu_smart_contract::GetAppResult;
+use crate::declarations::mu_smart_contract::GetAppUsagesResult;
+use crate::declarations::mu_smart_contract::GetAppsResult;
+use crate::declarations::mu_smart_contract::GetNotificationsResult;
+use crate::declarations::mu_smart_contract::Manifest;
+use crate::declarations::mu_smart_contract::NotificationKind;
+use crate::declarations::mu_smart_contract::QuoteCyclesResult;
+use crate::declarations::mu_smart_contract::QuoteDeployResult;
 use crate::declarations::mu_smart_contract::RemoveAppResult;
+use crate::declarations::mu_smart_contract::ReportUsageResult;
+use crate::declarations::mu_smart_contract::RequestCyclesResult;
+use crate::declarations::mu_smart_contract::ServiceUsage;
+use crate::declarations::mu_smart_contract::SetAppBudgetResult;
+use crate::declarations::mu_smart_contract::SetAppCyclesPolicyResult;
+use crate::declarations::mu_smart_contract::SetStandingAllowanceResult;
+use crate::declarations::mu_smart_contract::SettingsUpdate;
+use crate::declarations::mu_smart_contract::UpdateSettingsResult;
+use crate::declarations::mu_smart_contract::UpgradeAppResult;
+use crate::declarations::mu_smart_contract::UploadResult;
+use crate::declarations::mu_smart_contract::UsageKind;
+use crate::declarations::mu_smart_contract::UsageReport;
+use candid::CandidType;
+use candid::Deserialize;
+use candid::Encode;
+use candid::Nat;
+use candid::Principal;
 use ic_ledger_types::AccountIdentifier;
+use ic_ledger_types::Subaccount;
 use ic_ledger_types::Tokens;
 use ic_ledger_types::DEFAULT_FEE;
 use ic_ledger_types::DEFAULT_SUBACCOUNT;
+use ic_ledger_types::MAINNET_CYCLES_MINTING_CANISTER_ID;
 use pocket_ic::call_candid_as;
 use pocket_ic::common::rest::RawEffectivePrincipal;
+use pocket_ic::WasmResult;
 use serde_bytes::ByteBuf;
+use sha2::Digest;
+use sha2::Sha256;
+use std::time::Duration;
 
 #[test]
 fn test_can_deploy_canister() {
@@ -128,25 +178,67 @@ fn test_developers_can_withdraw_from_their_escrow_account() {
         developer_initial_balance - (DEFAULT_FEE + DEFAULT_FEE),
         test_case.ledger_balance_of(developer1_account)
     );
-}
 
-#[test]
-fn test_can_manage_app() {
-    let test_case = TestCase::setup_with_registered_developer1();
-    let developer_info = match call_candid_as::<_, (GetDeveloperResult,)>(
+    // The ICRC-1 escrow account is the same ledger account
+    let escrow_icrc1_subaccount = developer_info
+        .escrow_icrc1_account
+        .subaccount
+        .as_ref()
+        .map(|s| Subaccount(s.as_slice().try_into().unwrap()))
+        .unwrap();
+    assert_eq!(
+        test_case.mu_smart_contract,
+        developer_info.escrow_icrc1_account.owner
+    );
+    assert_eq!(
+        escrow_account,
+        AccountIdentifier::new(&test_case.mu_smart_contract, &escrow_icrc1_subaccount)
+    );
+
+    // Withdraw to an ICRC-1 account
+    test_case
+        .ledger_transfer(
+            test_case.developer1,
+            None,
+            escrow_account,
+            Tokens::from_e8s(250_000),
+        )
+        .unwrap();
+
+    match call_candid_as::<_, (RequestEscrowWithdrawIcrc1Result,)>(
         &test_case.pic,
         test_case.mu_smart_contract,
         RawEffectivePrincipal::None,
         test_case.developer1,
-        "get_developer",
-        ((),),
+        "request_escrow_withdraw_icrc1",
+        (
+            Account {
+                owner: test_case.developer1,
+                subaccount: None,
+            },
+            Nat::from(250_000 - DEFAULT_FEE.e8s()),
+        ),
     )
     .unwrap()
     {
-        (GetDeveloperResult::Ok(i),) => i,
-        (GetDeveloperResult::Err(e),) => panic!("canister call failed: {e:?}"),
+        (RequestEscrowWithdrawIcrc1Result::Ok(i),) => i,
+        (RequestEscrowWithdrawIcrc1Result::Err(e),) => panic!("canister call failed: {e:?}"),
     };
 
+    assert_eq!(
+        Tokens::from_e8s(0),
+        test_case.ledger_balance_of(escrow_account)
+    );
+    assert_eq!(
+        developer_initial_balance - (DEFAULT_FEE + DEFAULT_FEE + DEFAULT_FEE + DEFAULT_FEE),
+        test_case.ledger_balance_of(developer1_account)
+    );
+}
+
+#[test]
+fn test_can_deploy_app() {
+    let test_case = TestCase::setup_with_registered_developer1();
+
     // Can not deploy if doesn't have enough balance in escrow account
     match call_candid_as::<_, (Result_,)>(
         &test_case.pic,
@@ -156,16 +248,17 @@ fn test_can_manage_app() {
         "deploy_app",
         (DeployAppRequest {
             name: String::from("TestApp"),
-            app_data: ByteBuf::from(b"invalid code"),
+            app_data: AppData::Inline(ByteBuf::from(app_package(
+                "TestApp",
+                "0.1.0",
+                &test_wasm_module(),
+            ))),
         },),
     )
     .unwrap()
     {
         (Result_::Err(Error::InsufficientBalanceForDeploy { was, needed }),)
-            if was.e8s == 0 && needed.e8s == 1_000_000_000 =>
-        {
-            ()
-        }
+            if was.e8s == 0 && needed.e8s == 1_000_000_000 => {}
         (Result_::Ok(_),) => {
             panic!("Invalid result, should fail with `InsufficientBalanceForDeploy`")
         }
@@ -173,7 +266,7 @@ fn test_can_manage_app() {
     };
 
     // Deposit ICP tokens to developer escrow account
-    let escrow_account = AccountIdentifier::from_slice(&developer_info.escrow_account).unwrap();
+    let escrow_account = test_case.escrow_account_of(test_case.developer1);
     assert_eq!(
         Tokens::from_e8s(0),
         test_case.ledger_balance_of(escrow_account)
@@ -193,6 +286,48 @@ fn test_can_manage_app() {
         test_case.ledger_balance_of(escrow_account)
     );
 
+    // Packages that can not be decoded are rejected
+    match call_candid_as::<_, (Result_,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "deploy_app",
+        (DeployAppRequest {
+            name: String::from("TestApp"),
+            app_data: AppData::Inline(ByteBuf::from(b"invalid code")),
+        },),
+    )
+    .unwrap()
+    {
+        (Result_::Err(Error::InvalidAppPackage(_)),) => {}
+        (Result_::Ok(_),) => panic!("Invalid result, should fail with `InvalidAppPackage`"),
+        (Result_::Err(e),) => panic!("canister call failed: {e:?}"),
+    };
+
+    // Packages with a wasm module that can not run on the IC are rejected
+    match call_candid_as::<_, (Result_,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "deploy_app",
+        (DeployAppRequest {
+            name: String::from("TestApp"),
+            app_data: AppData::Inline(ByteBuf::from(app_package(
+                "TestApp",
+                "0.1.0",
+                b"invalid code",
+            ))),
+        },),
+    )
+    .unwrap()
+    {
+        (Result_::Err(Error::InvalidWasmModule(_)),) => {}
+        (Result_::Ok(_),) => panic!("Invalid result, should fail with `InvalidWasmModule`"),
+        (Result_::Err(e),) => panic!("canister call failed: {e:?}"),
+    };
+
     // After depositing some tokens, we can deploy again
     let app_id = match call_candid_as::<_, (Result_,)>(
         &test_case.pic,
@@ -202,7 +337,11 @@ fn test_can_manage_app() {
         "deploy_app",
         (DeployAppRequest {
             name: String::from("TestApp"),
-            app_data: ByteBuf::from(b"invalid code"),
+            app_data: AppData::Inline(ByteBuf::from(app_package(
+                "TestApp",
+                "0.1.0",
+                &test_wasm_module(),
+            ))),
         },),
     )
     .unwrap()
@@ -222,34 +361,1775 @@ fn test_can_manage_app() {
     )
     .unwrap()
     {
-        (GetAppResult::Ok(a),) => assert_eq!(
-            Some(AppDto {
-                id: app_id,
-                usages: vec![],
-                state: AppState::Active {
+        (GetAppResult::Ok(Some(AppDto {
+            id,
+            state,
+            cycles_policy,
+            ..
+        })),) => {
+            assert_eq!(app_id, id);
+            // New apps can only request the amount of their cycles controller
+            assert_eq!(Some(200_000_000_000), cycles_policy.max_cycles_per_request);
+            assert_eq!(
+                AppState::Active {
                     name: String::from("TestApp"),
                     revision: 1,
+                    manifest: Manifest {
+                        name: String::from("TestApp"),
+                        version: String::from("0.1.0"),
+                        description: None,
+                    },
+                    previous_revisions: vec![],
                 },
-            }),
-            a
-        ),
+                state
+            );
+            assert!(test_case.pic.canister_exists(app_id));
+        }
+        (GetAppResult::Ok(None),) => panic!("app not found"),
         (GetAppResult::Err(e),) => panic!("canister call failed: {e:?}"),
     };
+}
 
-    // We can remove app
-    match call_candid_as::<_, (RemoveAppResult,)>(
+#[test]
+fn test_app_usages_are_listed_for_their_developer() {
+    let test_case = TestCase::setup_with_registered_developer1();
+    let app_id = test_case.deploy_funded_app(test_case.developer1, "TestApp");
+
+    // Canister creation is charged from the escrow account, with the platform commission
+    match call_candid_as::<_, (GetAppUsagesResult,)>(
         &test_case.pic,
         test_case.mu_smart_contract,
         RawEffectivePrincipal::None,
         test_case.developer1,
-        "remove_app",
+        "get_app_usages",
+        (AppUsagesRequest {
+            app_id,
+            from: None,
+            to: None,
+            cursor: None,
+            limit: None,
+        },),
+    )
+    .unwrap()
+    {
+        (GetAppUsagesResult::Ok(AppUsagesPage { usages, next }),) => {
+            assert_eq!(1, usages.len());
+            assert_eq!(None, next);
+            assert_eq!(
+                (usages[0].amount.e8s * 500).div_ceil(10_000),
+                usages[0].commission.e8s
+            );
+            assert!(usages[0].is_paid);
+            assert_eq!(
+                Tokens::from_e8s(usages[0].commission.e8s),
+                test_case.ledger_balance_of(test_case.treasury_account)
+            );
+        }
+        (GetAppUsagesResult::Err(e),) => panic!("canister call failed: {e:?}"),
+    };
+
+    // Usages are only listed within the requested time range
+    match call_candid_as::<_, (GetAppUsagesResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "get_app_usages",
+        (AppUsagesRequest {
+            app_id,
+            from: None,
+            to: Some(mu_smart_contract::Timestamp { timestamp_nanos: 0 }),
+            cursor: None,
+            limit: Some(10),
+        },),
+    )
+    .unwrap()
+    {
+        (GetAppUsagesResult::Ok(AppUsagesPage { usages, next }),) => {
+            assert!(usages.is_empty());
+            assert_eq!(None, next);
+        }
+        (GetAppUsagesResult::Err(e),) => panic!("canister call failed: {e:?}"),
+    };
+
+    // Usages of the apps of other developers can not be read
+    let other_developer = random_principal();
+    call_candid_as::<_, (Result_,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        other_developer,
+        "register_developer",
+        (),
+    )
+    .unwrap();
+    match call_candid_as::<_, (GetAppUsagesResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        other_developer,
+        "get_app_usages",
+        (AppUsagesRequest {
+            app_id,
+            from: None,
+            to: None,
+            cursor: None,
+            limit: None,
+        },),
+    )
+    .unwrap()
+    {
+        (GetAppUsagesResult::Err(Error::AppNotFound),) => (),
+        _ => panic!("usages of another developer's app were listed"),
+    };
+}
+
+#[test]
+fn test_apps_are_listed_for_their_developer() {
+    let test_case = TestCase::setup_with_registered_developer1();
+    let app_id = test_case.deploy_funded_app(test_case.developer1, "TestApp");
+
+    let other_developer = random_principal();
+    call_candid_as::<_, (Result_,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        other_developer,
+        "register_developer",
+        (),
+    )
+    .unwrap();
+
+    // Apps are listed in pages
+    match call_candid_as::<_, (GetAppsResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "get_apps",
+        (None::<Principal>, Some(1_u64)),
+    )
+    .unwrap()
+    {
+        (GetAppsResult::Ok(AppsPage { apps, next }),) => {
+            assert_eq!(1, apps.len());
+            assert_eq!(app_id, apps[0].id);
+            assert_eq!(None, next);
+        }
+        (GetAppsResult::Err(e),) => panic!("canister call failed: {e:?}"),
+    };
+
+    // The apps of other developers are not listed
+    match call_candid_as::<_, (GetAppsResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        other_developer,
+        "get_apps",
+        (None::<Principal>, None::<u64>),
+    )
+    .unwrap()
+    {
+        (GetAppsResult::Ok(AppsPage { apps, next }),) => {
+            assert!(apps.is_empty());
+            assert_eq!(None, next);
+        }
+        (GetAppsResult::Err(e),) => panic!("canister call failed: {e:?}"),
+    };
+}
+
+#[test]
+fn test_can_upgrade_app() {
+    let test_case = TestCase::setup_with_registered_developer1();
+    let app_id = test_case.deploy_funded_app(test_case.developer1, "TestApp");
+
+    // We can upgrade app
+    match call_candid_as::<_, (UpgradeAppResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "upgrade_app",
+        (
+            app_id,
+            DeployAppRequest {
+                name: String::from("TestApp v2"),
+                app_data: AppData::Inline(ByteBuf::from(app_package(
+                    "TestApp",
+                    "0.2.0",
+                    &test_wasm_module(),
+                ))),
+            },
+        ),
+    )
+    .unwrap()
+    {
+        (UpgradeAppResult::Ok(revision),) => assert_eq!(2, revision),
+        (UpgradeAppResult::Err(e),) => panic!("canister call failed: {e:?}"),
+    };
+
+    match call_candid_as::<_, (GetAppResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "get_app",
         (app_id,),
     )
     .unwrap()
     {
-        (RemoveAppResult::Ok,) => (),
-        (RemoveAppResult::Err(e),) => panic!("canister call failed: {e:?}"),
+        (GetAppResult::Ok(Some(AppDto {
+            state:
+                AppState::Active {
+                    name,
+                    revision,
+                    manifest,
+                    previous_revisions,
+                },
+            ..
+        })),) => {
+            assert_eq!("TestApp v2", name);
+            assert_eq!(2, revision);
+            assert_eq!("0.2.0", manifest.version);
+            assert_eq!(1, previous_revisions.len());
+            assert_eq!("0.1.0", previous_revisions[0].version);
+            assert_eq!("TestApp", previous_revisions[0].name);
+            assert_eq!(1, previous_revisions[0].revision);
+        }
+        (GetAppResult::Ok(a),) => panic!("unexpected app state: {a:?}"),
+        (GetAppResult::Err(e),) => panic!("canister call failed: {e:?}"),
+    };
+}
+
+#[test]
+fn test_cycles_requests_above_the_app_policy_are_rejected() {
+    let test_case = TestCase::setup_with_registered_developer1();
+    let app_id = test_case.deploy_funded_app(test_case.developer1, "TestApp");
+
+    // Cycles requests of the app are limited by its policy
+    let policy = CyclesPolicy {
+        max_cycles_per_request: Some(1_000_000),
+        max_cycles_per_day: None,
+        cooldown_seconds: None,
+    };
+    let result = call_candid_as::<_, (SetAppCyclesPolicyResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "set_app_cycles_policy",
+        (random_principal(), &policy),
+    )
+    .unwrap();
+    assert_eq!(SetAppCyclesPolicyResult::Err(Error::AppNotFound), result.0);
+
+    let result = call_candid_as::<_, (SetAppCyclesPolicyResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "set_app_cycles_policy",
+        (app_id, &policy),
+    )
+    .unwrap();
+    assert_eq!(SetAppCyclesPolicyResult::Ok, result.0);
+
+    let result = call_candid_as::<_, (RequestCyclesResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        app_id,
+        "request_cycles",
+        (2_000_000_u64,),
+    )
+    .unwrap();
+    assert_eq!(
+        RequestCyclesResult::Err(Error::CyclesRequestAboveLimit { max: 1_000_000 }),
+        result.0
+    );
+}
+
+#[test]
+fn test_app_spending_is_limited_by_its_budget() {
+    let test_case = TestCase::setup_with_registered_developer1();
+    let app_id = test_case.deploy_funded_app(test_case.developer1, "TestApp");
+
+    // Spending of the app is limited by its budget
+    let set_app_budget = |budget: AppBudget| {
+        call_candid_as::<_, (SetAppBudgetResult,)>(
+            &test_case.pic,
+            test_case.mu_smart_contract,
+            RawEffectivePrincipal::None,
+            test_case.developer1,
+            "set_app_budget",
+            (app_id, Some(budget)),
+        )
+        .unwrap()
+        .0
+    };
+    assert!(matches!(
+        set_app_budget(AppBudget {
+            amount: mu_smart_contract::Tokens { e8s: 1 },
+            period_seconds: 0,
+        }),
+        SetAppBudgetResult::Err(Error::InvalidBudget(_))
+    ));
+    assert_eq!(
+        SetAppBudgetResult::Ok,
+        set_app_budget(AppBudget {
+            amount: mu_smart_contract::Tokens { e8s: 1 },
+            period_seconds: 24 * 60 * 60,
+        })
+    );
+
+    let result = call_candid_as::<_, (RequestCyclesResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        app_id,
+        "request_cycles",
+        (1_000_000_u64,),
+    )
+    .unwrap();
+    assert_eq!(
+        RequestCyclesResult::Err(Error::AppBudgetExceeded {
+            remaining: mu_smart_contract::Tokens { e8s: 1 }
+        }),
+        result.0
+    );
+
+    // The developer is notified of the exceeded budget
+    let get_notifications = || match call_candid_as::<_, (GetNotificationsResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "get_notifications",
+        ((),),
+    )
+    .unwrap()
+    {
+        (GetNotificationsResult::Ok(n),) => n,
+        (GetNotificationsResult::Err(e),) => panic!("canister call failed: {e:?}"),
     };
+    let notifications = get_notifications();
+    assert_eq!(1, notifications.len());
+    assert!(matches!(
+        &notifications[0].kind,
+        NotificationKind::AppBudgetExceeded { app_id: id, .. } if *id == app_id
+    ));
+
+    let result = call_candid_as::<_, (ClearNotificationsResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "clear_notifications",
+        (notifications[0].id,),
+    )
+    .unwrap();
+    assert_eq!(ClearNotificationsResult::Ok, result.0);
+    assert!(get_notifications().is_empty());
 }
 
-// TODO: Add test for `Request cycles` functionality
+#[test]
+fn test_can_remove_app() {
+    let test_case = TestCase::setup_with_registered_developer1();
+    let app_id = test_case.deploy_funded_app(test_case.developer1, "TestApp");
+
+    // Other developers can not remove the app
+    let developer2 = random_principal();
+    let result = call_candid_as::<_, (Result_,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        developer2,
+        "register_developer",
+        ((),),
+    )
+    .unwrap();
+    assert_eq!(Result_::Ok(developer2), result.0);
+    let result = call_candid_as::<_, (RemoveAppResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        developer2,
+        "remove_app",
+        (app_id,),
+    )
+    .unwrap();
+    assert_eq!(RemoveAppResult::Err(Error::AppNotFound), result.0);
+
+    // We can remove app
+    match call_candid_as::<_, (RemoveAppResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "remove_app",
+        (app_id,),
+    )
+    .unwrap()
+    {
+        (RemoveAppResult::Ok,) => (),
+        (RemoveAppResult::Err(e),) => panic!("canister call failed: {e:?}"),
+    };
+
+    // The removed app is kept with its usages
+    match call_candid_as::<_, (GetAppResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "get_app",
+        (app_id,),
+    )
+    .unwrap()
+    {
+        (GetAppResult::Ok(Some(AppDto { state, .. })),) => assert_eq!(AppState::Deleted, state),
+        (GetAppResult::Ok(None),) => panic!("app not found"),
+        (GetAppResult::Err(e),) => panic!("canister call failed: {e:?}"),
+    };
+    match call_candid_as::<_, (GetAppUsagesResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "get_app_usages",
+        (AppUsagesRequest {
+            app_id,
+            from: None,
+            to: None,
+            cursor: None,
+            limit: None,
+        },),
+    )
+    .unwrap()
+    {
+        (GetAppUsagesResult::Ok(AppUsagesPage { usages, .. }),) => assert!(!usages.is_empty()),
+        (GetAppUsagesResult::Err(e),) => panic!("canister call failed: {e:?}"),
+    };
+}
+
+#[test]
+fn test_apps_that_failed_to_install_are_installed_by_an_upgrade() {
+    let test_case = TestCase::setup_with_registered_developer1();
+    let escrow_account = test_case.escrow_account_of(test_case.developer1);
+    test_case
+        .ledger_transfer(
+            test_case.developer1,
+            None,
+            escrow_account,
+            Tokens::from_e8s(1_000_000_000),
+        )
+        .unwrap();
+
+    // The canister is created and paid for, but its code traps on install
+    match call_candid_as::<_, (Result_,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "deploy_app",
+        (DeployAppRequest {
+            name: String::from("TestApp"),
+            app_data: AppData::Inline(ByteBuf::from(app_package(
+                "TestApp",
+                "0.1.0",
+                &trapping_init_wasm_module(),
+            ))),
+        },),
+    )
+    .unwrap()
+    {
+        (Result_::Err(Error::Internal(_)),) => {}
+        result => panic!("unexpected deploy result: {result:?}"),
+    };
+
+    // The app stays with the developer
+    let app_id = match call_candid_as::<_, (GetAppsResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "get_apps",
+        (None::<Principal>, None::<u64>),
+    )
+    .unwrap()
+    {
+        (GetAppsResult::Ok(AppsPage { apps, .. }),) => {
+            assert_eq!(1, apps.len());
+            apps[0].id
+        }
+        (GetAppsResult::Err(e),) => panic!("canister call failed: {e:?}"),
+    };
+
+    // Upgrading it installs the code from scratch
+    match call_candid_as::<_, (UpgradeAppResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "upgrade_app",
+        (
+            app_id,
+            DeployAppRequest {
+                name: String::from("TestApp"),
+                app_data: AppData::Inline(ByteBuf::from(app_package(
+                    "TestApp",
+                    "0.1.1",
+                    &test_wasm_module(),
+                ))),
+            },
+        ),
+    )
+    .unwrap()
+    {
+        (UpgradeAppResult::Ok(revision),) => assert_eq!(2, revision),
+        (UpgradeAppResult::Err(e),) => panic!("canister call failed: {e:?}"),
+    };
+    assert!(matches!(
+        test_case
+            .pic
+            .query_call(app_id, Principal::anonymous(), "ping", Encode!().unwrap()),
+        Ok(WasmResult::Reply(_))
+    ));
+}
+
+#[test]
+fn test_can_upload_app_in_chunks() {
+    let test_case = TestCase::setup_with_registered_developer1();
+    let app_data = vec![7_u8; 3 * 1024 * 1024];
+
+    let upload_id = match call_candid_as::<_, (BeginUploadResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "begin_upload",
+        (app_data.len() as u64,),
+    )
+    .unwrap()
+    {
+        (BeginUploadResult::Ok(i),) => i,
+        (BeginUploadResult::Err(e),) => panic!("canister call failed: {e:?}"),
+    };
+
+    for chunk in app_data.chunks(1024 * 1024) {
+        let result = call_candid_as::<_, (UploadResult,)>(
+            &test_case.pic,
+            test_case.mu_smart_contract,
+            RawEffectivePrincipal::None,
+            test_case.developer1,
+            "append_upload_chunk",
+            (upload_id, ByteBuf::from(chunk)),
+        )
+        .unwrap();
+        assert_eq!(UploadResult::Ok, result.0);
+    }
+
+    // Can not upload more than the announced size
+    let result = call_candid_as::<_, (UploadResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "append_upload_chunk",
+        (upload_id, ByteBuf::from(vec![0_u8])),
+    )
+    .unwrap();
+    assert_eq!(
+        UploadResult::Err(Error::UploadSizeLimitExceeded {
+            max: app_data.len() as u64
+        }),
+        result.0
+    );
+
+    // Finalizing fails if the hash doesn't match
+    let result = call_candid_as::<_, (UploadResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "finalize_upload",
+        (
+            upload_id,
+            ByteBuf::from(Sha256::digest(b"other data").to_vec()),
+        ),
+    )
+    .unwrap();
+    assert_eq!(UploadResult::Err(Error::UploadHashMismatch), result.0);
+
+    let result = call_candid_as::<_, (UploadResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "finalize_upload",
+        (upload_id, ByteBuf::from(Sha256::digest(&app_data).to_vec())),
+    )
+    .unwrap();
+    assert_eq!(UploadResult::Ok, result.0);
+
+    // Other principals can not use the upload
+    let result = call_candid_as::<_, (UploadResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        random_principal(),
+        "cancel_upload",
+        (upload_id,),
+    )
+    .unwrap();
+    assert_eq!(UploadResult::Err(Error::DeveloperAccountNotFound), result.0);
+}
+
+#[test]
+fn test_developers_have_a_limited_number_of_uploads() {
+    let test_case = TestCase::setup_with_registered_developer1();
+    let begin_upload = || {
+        call_candid_as::<_, (BeginUploadResult,)>(
+            &test_case.pic,
+            test_case.mu_smart_contract,
+            RawEffectivePrincipal::None,
+            test_case.developer1,
+            "begin_upload",
+            (1024_u64,),
+        )
+        .unwrap()
+        .0
+    };
+    let upload_ids = (0..3)
+        .map(|_| match begin_upload() {
+            BeginUploadResult::Ok(i) => i,
+            BeginUploadResult::Err(e) => panic!("canister call failed: {e:?}"),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        BeginUploadResult::Err(Error::TooManyUploads { max: 3 }),
+        begin_upload()
+    );
+
+    // Cancelling an upload frees its place, and its ID is not reused
+    let result = call_candid_as::<_, (UploadResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "cancel_upload",
+        (upload_ids[2],),
+    )
+    .unwrap();
+    assert_eq!(UploadResult::Ok, result.0);
+    match begin_upload() {
+        BeginUploadResult::Ok(i) => assert!(i > upload_ids[2]),
+        BeginUploadResult::Err(e) => panic!("canister call failed: {e:?}"),
+    }
+
+    // Uploads expire after a day
+    test_case
+        .pic
+        .advance_time(Duration::from_secs(24 * 60 * 60));
+    test_case.pic.tick();
+    let result = call_candid_as::<_, (UploadResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "append_upload_chunk",
+        (upload_ids[0], ByteBuf::from(vec![0_u8])),
+    )
+    .unwrap();
+    assert_eq!(UploadResult::Err(Error::UploadNotFound), result.0);
+    assert!(matches!(begin_upload(), BeginUploadResult::Ok(_)));
+}
+
+#[test]
+fn test_cycles_requests_of_apps_are_limited_by_their_policy() {
+    let test_case = TestCase::setup_with_registered_developer1();
+    let escrow_account = test_case.escrow_account_of(test_case.developer1);
+    test_case
+        .ledger_transfer(
+            test_case.developer1,
+            None,
+            escrow_account,
+            Tokens::from_e8s(1_000_000_000),
+        )
+        .unwrap();
+    let app_id = match test_case.deploy_app(test_case.developer1, "TestApp") {
+        Result_::Ok(app_id) => app_id,
+        Result_::Err(e) => panic!("canister call failed: {e:?}"),
+    };
+
+    let request_cycles = |cycles: u64| {
+        call_candid_as::<_, (RequestCyclesResult,)>(
+            &test_case.pic,
+            test_case.mu_smart_contract,
+            RawEffectivePrincipal::None,
+            app_id,
+            "request_cycles",
+            (cycles,),
+        )
+        .unwrap()
+        .0
+    };
+
+    // New apps wait the cooldown from the settings between two requests
+    let cycles_before = test_case.pic.cycle_balance(app_id);
+    assert!(matches!(
+        request_cycles(200_000_000_000),
+        RequestCyclesResult::Ok(_)
+    ));
+    assert!(test_case.pic.cycle_balance(app_id) > cycles_before);
+    assert!(matches!(
+        request_cycles(200_000_000_000),
+        RequestCyclesResult::Err(Error::CyclesRequestTooSoon { .. })
+    ));
+
+    test_case.pic.advance_time(Duration::from_secs(5 * 60));
+    assert!(matches!(
+        request_cycles(200_000_000_000),
+        RequestCyclesResult::Ok(_)
+    ));
+
+    // The requests of the day count against a daily limit set later
+    let policy = CyclesPolicy {
+        max_cycles_per_request: None,
+        max_cycles_per_day: Some(500_000_000_000),
+        cooldown_seconds: None,
+    };
+    let result = call_candid_as::<_, (SetAppCyclesPolicyResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "set_app_cycles_policy",
+        (app_id, &policy),
+    )
+    .unwrap();
+    assert_eq!(SetAppCyclesPolicyResult::Ok, result.0);
+
+    assert_eq!(
+        RequestCyclesResult::Err(Error::DailyCyclesLimitReached {
+            remaining: 100_000_000_000
+        }),
+        request_cycles(200_000_000_000)
+    );
+    assert!(matches!(
+        request_cycles(100_000_000_000),
+        RequestCyclesResult::Ok(_)
+    ));
+}
+
+#[test]
+fn test_only_managers_can_report_usage() {
+    let test_case = TestCase::setup_with_registered_developer1();
+    let reports = vec![UsageReport {
+        app_id: random_principal(),
+        usage: ServiceUsage::KvStore {
+            reads: 1_000,
+            writes: 100,
+            stored_bytes: 1024,
+        },
+    }];
+
+    let result = call_candid_as::<_, (ReportUsageResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "report_usage",
+        ("report-1", &reports),
+    )
+    .unwrap();
+    assert_eq!(ReportUsageResult::Err(Error::Unauthorized), result.0);
+
+    // Reports of unknown apps are rejected
+    let result = call_candid_as::<_, (ReportUsageResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.manager,
+        "report_usage",
+        ("report-1", reports),
+    )
+    .unwrap();
+    assert_eq!(ReportUsageResult::Err(Error::AppNotFound), result.0);
+
+    // An empty report has nothing to charge
+    let result = call_candid_as::<_, (ReportUsageResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.manager,
+        "report_usage",
+        ("report-2", Vec::<UsageReport>::new()),
+    )
+    .unwrap();
+    assert_eq!(ReportUsageResult::Ok, result.0);
+}
+
+#[test]
+fn test_reported_usage_is_charged_from_the_escrow_account() {
+    let test_case = TestCase::setup_with_registered_developer1();
+    let escrow_account = test_case.escrow_account_of(test_case.developer1);
+    test_case
+        .ledger_transfer(
+            test_case.developer1,
+            None,
+            escrow_account,
+            Tokens::from_e8s(1_000_000_000),
+        )
+        .unwrap();
+    let app_id = match test_case.deploy_app(test_case.developer1, "TestApp") {
+        Result_::Ok(app_id) => app_id,
+        Result_::Err(e) => panic!("canister call failed: {e:?}"),
+    };
+    let escrow_balance = test_case.ledger_balance_of(escrow_account);
+    let treasury_balance = test_case.ledger_balance_of(test_case.treasury_account);
+
+    // A million reads cost 10_000 e8s, with a commission of 5%
+    let usage = || ServiceUsage::KvStore {
+        reads: 1_000_000,
+        writes: 0,
+        stored_bytes: 0,
+    };
+    let report_usage = |report_id: &str, usage: ServiceUsage| {
+        call_candid_as::<_, (ReportUsageResult,)>(
+            &test_case.pic,
+            test_case.mu_smart_contract,
+            RawEffectivePrincipal::None,
+            test_case.manager,
+            "report_usage",
+            (report_id, vec![UsageReport { app_id, usage }]),
+        )
+        .unwrap()
+        .0
+    };
+    assert_eq!(ReportUsageResult::Ok, report_usage("report-1", usage()));
+
+    // Retrying the report charges nothing more, and its ID can not be reused for another report
+    assert_eq!(ReportUsageResult::Ok, report_usage("report-1", usage()));
+    assert_eq!(
+        ReportUsageResult::Err(Error::IdempotencyKeyConflict),
+        report_usage(
+            "report-1",
+            ServiceUsage::BlobStorage {
+                stored_bytes: 0,
+                downloaded_bytes: 1,
+            }
+        )
+    );
+
+    assert_eq!(
+        escrow_balance - Tokens::from_e8s(10_000 + 500) - DEFAULT_FEE - DEFAULT_FEE,
+        test_case.ledger_balance_of(escrow_account)
+    );
+    assert_eq!(
+        treasury_balance + Tokens::from_e8s(500),
+        test_case.ledger_balance_of(test_case.treasury_account)
+    );
+
+    match call_candid_as::<_, (GetAppUsagesResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "get_app_usages",
+        (AppUsagesRequest {
+            app_id,
+            from: None,
+            to: None,
+            cursor: None,
+            limit: None,
+        },),
+    )
+    .unwrap()
+    {
+        (GetAppUsagesResult::Ok(AppUsagesPage { usages, .. }),) => {
+            // After the canister creation
+            assert_eq!(2, usages.len());
+            assert_eq!(
+                UsageKind::AdditionalServices { usage: usage() },
+                usages[1].kind
+            );
+            assert_eq!(10_000, usages[1].amount.e8s);
+            assert_eq!(500, usages[1].commission.e8s);
+            assert!(usages[1].is_paid);
+        }
+        (GetAppUsagesResult::Err(e),) => panic!("canister call failed: {e:?}"),
+    };
+}
+
+#[test]
+fn test_unpaid_usage_is_settled_once_the_escrow_can_pay() {
+    let test_case = TestCase::setup_with_registered_developer1();
+    let escrow_account = test_case.escrow_account_of(test_case.developer1);
+    test_case
+        .ledger_transfer(
+            test_case.developer1,
+            None,
+            escrow_account,
+            Tokens::from_e8s(1_000_000_000),
+        )
+        .unwrap();
+    let app_id = match test_case.deploy_app(test_case.developer1, "TestApp") {
+        Result_::Ok(app_id) => app_id,
+        Result_::Err(e) => panic!("canister call failed: {e:?}"),
+    };
+
+    // The escrow account is emptied
+    let developer1_account = AccountIdentifier::new(&test_case.developer1, &DEFAULT_SUBACCOUNT);
+    let escrow_balance = test_case.ledger_balance_of(escrow_account);
+    match call_candid_as::<_, (RequestEscrowWithdrawResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "request_escrow_withdraw",
+        (
+            developer1_account,
+            mu_smart_contract::Tokens {
+                e8s: escrow_balance.e8s() - DEFAULT_FEE.e8s(),
+            },
+        ),
+    )
+    .unwrap()
+    {
+        (RequestEscrowWithdrawResult::Ok(_),) => {}
+        (RequestEscrowWithdrawResult::Err(e),) => panic!("canister call failed: {e:?}"),
+    };
+
+    let report_usage = || {
+        call_candid_as::<_, (ReportUsageResult,)>(
+            &test_case.pic,
+            test_case.mu_smart_contract,
+            RawEffectivePrincipal::None,
+            test_case.manager,
+            "report_usage",
+            (
+                "report-1",
+                vec![UsageReport {
+                    app_id,
+                    usage: ServiceUsage::KvStore {
+                        reads: 1_000_000,
+                        writes: 0,
+                        stored_bytes: 0,
+                    },
+                }],
+            ),
+        )
+        .unwrap()
+        .0
+    };
+    let is_paid = || {
+        match call_candid_as::<_, (GetAppUsagesResult,)>(
+            &test_case.pic,
+            test_case.mu_smart_contract,
+            RawEffectivePrincipal::None,
+            test_case.developer1,
+            "get_app_usages",
+            (AppUsagesRequest {
+                app_id,
+                from: None,
+                to: None,
+                cursor: None,
+                limit: None,
+            },),
+        )
+        .unwrap()
+        {
+            (GetAppUsagesResult::Ok(AppUsagesPage { usages, .. }),) => {
+                // After the canister creation, the usage is recorded once
+                assert_eq!(2, usages.len());
+                usages[1].is_paid
+            }
+            (GetAppUsagesResult::Err(e),) => panic!("canister call failed: {e:?}"),
+        }
+    };
+
+    // The usage is recorded as unpaid, retrying the report retries the transfer
+    assert!(matches!(
+        report_usage(),
+        ReportUsageResult::Err(Error::Internal(_))
+    ));
+    assert!(!is_paid());
+    assert!(matches!(
+        report_usage(),
+        ReportUsageResult::Err(Error::Internal(_))
+    ));
+    assert!(!is_paid());
+
+    // Settled by the timer once the escrow account is funded again
+    test_case
+        .ledger_transfer(
+            test_case.developer1,
+            None,
+            escrow_account,
+            Tokens::from_e8s(100_000),
+        )
+        .unwrap();
+    let treasury_balance = test_case.ledger_balance_of(test_case.treasury_account);
+    test_case.pic.advance_time(Duration::from_secs(5 * 60));
+    for _ in 0..10 {
+        test_case.pic.tick();
+    }
+    assert!(is_paid());
+    assert_eq!(
+        Tokens::from_e8s(100_000 - 10_000 - 500) - DEFAULT_FEE - DEFAULT_FEE,
+        test_case.ledger_balance_of(escrow_account)
+    );
+    assert_eq!(
+        treasury_balance + Tokens::from_e8s(500),
+        test_case.ledger_balance_of(test_case.treasury_account)
+    );
+
+    // The report is charged, retrying it changes nothing
+    assert_eq!(ReportUsageResult::Ok, report_usage());
+    assert!(is_paid());
+}
+
+#[test]
+fn test_developers_can_not_deploy_more_than_the_maximum_number_of_apps() {
+    let test_case = TestCase::setup_with_registered_developer1();
+    test_case
+        .ledger_transfer(
+            test_case.developer1,
+            None,
+            test_case.escrow_account_of(test_case.developer1),
+            Tokens::from_e8s(5_000_000_000),
+        )
+        .unwrap();
+
+    // Up to `max_apps_per_developer` apps
+    for name in ["TestApp1", "TestApp2"] {
+        if let Result_::Err(e) = test_case.deploy_app(test_case.developer1, name) {
+            panic!("canister call failed: {e:?}");
+        }
+    }
+
+    match test_case.deploy_app(test_case.developer1, "TestApp3") {
+        Result_::Err(Error::MaxAppsCountReached) => {}
+        r => panic!("Invalid result, should fail with `MaxAppsCountReached`: {r:?}"),
+    }
+}
+
+#[test]
+fn test_settings_persist_across_upgrades() {
+    let test_case = TestCase::setup_with_registered_developer1();
+    let deploy_app = || {
+        call_candid_as::<_, (Result_,)>(
+            &test_case.pic,
+            test_case.mu_smart_contract,
+            RawEffectivePrincipal::None,
+            test_case.developer1,
+            "deploy_app",
+            (DeployAppRequest {
+                name: String::from("TestApp"),
+                app_data: AppData::Inline(ByteBuf::from(app_package(
+                    "TestApp",
+                    "0.1.0",
+                    &test_wasm_module(),
+                ))),
+            },),
+        )
+        .unwrap()
+        .0
+    };
+
+    // Upgrading without arguments keeps the settings and the developers
+    test_case.upgrade_mu_smart_contract(Encode!(&None::<SettingsUpdate>).unwrap());
+    match deploy_app() {
+        Result_::Err(Error::InsufficientBalanceForDeploy { needed, .. }) => {
+            assert_eq!(1_000_000_000, needed.e8s)
+        }
+        r => panic!("Invalid result, should fail with `InsufficientBalanceForDeploy`: {r:?}"),
+    }
+
+    // Settings can be overridden on upgrade
+    test_case.upgrade_mu_smart_contract(
+        Encode!(&Some(SettingsUpdate {
+            minimum_escrow_balance_for_deploy: Some(mu_smart_contract::Tokens {
+                e8s: 2_000_000_000,
+            }),
+            ..empty_settings_update()
+        }))
+        .unwrap(),
+    );
+    match deploy_app() {
+        Result_::Err(Error::InsufficientBalanceForDeploy { needed, .. }) => {
+            assert_eq!(2_000_000_000, needed.e8s)
+        }
+        r => panic!("Invalid result, should fail with `InsufficientBalanceForDeploy`: {r:?}"),
+    }
+}
+
+#[test]
+fn test_can_upgrade_from_the_first_version() {
+    let test_case = TestCase::setup();
+    test_case.reinstall_mu_smart_contract_baseline();
+
+    #[derive(CandidType, Deserialize)]
+    struct BaselineDeveloperDto {
+        escrow_account: ByteBuf,
+    }
+    let (result,) = call_candid_as::<_, (Result_,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "register_developer",
+        ((),),
+    )
+    .unwrap();
+    assert_eq!(Result_::Ok(test_case.developer1), result);
+    let (baseline_developer,) =
+        call_candid_as::<_, (std::result::Result<BaselineDeveloperDto, Error>,)>(
+            &test_case.pic,
+            test_case.mu_smart_contract,
+            RawEffectivePrincipal::None,
+            test_case.developer1,
+            "get_developer",
+            ((),),
+        )
+        .unwrap();
+    let escrow_account = baseline_developer.unwrap().escrow_account;
+
+    // The first version kept its settings on the heap, so upgrading needs all of them
+    assert!(test_case
+        .try_upgrade_mu_smart_contract(Encode!(&None::<SettingsUpdate>).unwrap())
+        .is_err());
+    assert!(test_case
+        .try_upgrade_mu_smart_contract(
+            Encode!(&Some(SettingsUpdate {
+                max_apps_per_developer: Some(2),
+                ..empty_settings_update()
+            }))
+            .unwrap()
+        )
+        .is_err());
+
+    test_case.upgrade_mu_smart_contract(
+        Encode!(&Some(SettingsUpdate {
+            minimum_escrow_balance_for_deploy: Some(mu_smart_contract::Tokens {
+                e8s: 1_000_000_000
+            }),
+            max_apps_per_developer: Some(2),
+            commission_rate_bps: Some(500),
+            treasury_account: Some(ByteBuf::from(test_case.treasury_account.as_ref())),
+            exchange_rate_timeout_seconds: Some(10),
+            max_wasm_module_size: Some(10 * 1024 * 1024),
+            cycles_request_threshold: Some(100_000_000_000),
+            cycles_request_amount: Some(200_000_000_000),
+            max_cycles_per_day: Some(1_000_000_000_000),
+            cycles_request_cooldown_seconds: Some(5 * 60),
+            manager_canisters: Some(vec![test_case.manager]),
+            service_prices: Some(mu_smart_contract::ServicePrices {
+                kv_store_reads_per_million: mu_smart_contract::Tokens { e8s: 10_000 },
+                kv_store_writes_per_million: mu_smart_contract::Tokens { e8s: 100_000 },
+                kv_store_storage_per_gib: mu_smart_contract::Tokens { e8s: 1_000_000 },
+                blob_storage_storage_per_gib: mu_smart_contract::Tokens { e8s: 500_000 },
+                blob_storage_download_per_gib: mu_smart_contract::Tokens { e8s: 1_000_000 },
+            }),
+            ledger_canister_id: Some(test_case.ledger_canister),
+            ..empty_settings_update()
+        }))
+        .unwrap(),
+    );
+
+    // The developers registered before are kept
+    match call_candid_as::<_, (GetDeveloperResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "get_developer",
+        ((),),
+    )
+    .unwrap()
+    {
+        (GetDeveloperResult::Ok(developer),) => {
+            assert_eq!(escrow_account, developer.escrow_account)
+        }
+        (GetDeveloperResult::Err(e),) => panic!("canister call failed: {e:?}"),
+    }
+
+    // The settings are kept for the next upgrades
+    test_case.upgrade_mu_smart_contract(Encode!(&None::<SettingsUpdate>).unwrap());
+    match call_candid_as::<_, (GetSettingsResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        Principal::anonymous(),
+        "get_settings",
+        ((),),
+    )
+    .unwrap()
+    {
+        (GetSettingsResult::Ok(settings),) => {
+            assert_eq!(2, settings.max_apps_per_developer);
+            assert_eq!(test_case.ledger_canister, settings.ledger_canister_id);
+        }
+        (GetSettingsResult::Err(e),) => panic!("canister call failed: {e:?}"),
+    }
+}
+
+#[test]
+fn test_admins_can_update_settings() {
+    let test_case = TestCase::setup_with_registered_developer1();
+    let update = SettingsUpdate {
+        max_apps_per_developer: Some(5),
+        ..empty_settings_update()
+    };
+
+    // Developers are not admins
+    let result = call_candid_as::<_, (UpdateSettingsResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "update_settings",
+        (&update,),
+    )
+    .unwrap();
+    assert_eq!(UpdateSettingsResult::Err(Error::Unauthorized), result.0);
+
+    // The principal that installed the canister is an admin
+    let result = call_candid_as::<_, (AdminResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        Principal::anonymous(),
+        "add_admin",
+        (test_case.developer1,),
+    )
+    .unwrap();
+    assert_eq!(AdminResult::Ok, result.0);
+
+    // Invalid settings are rejected
+    let result = call_candid_as::<_, (UpdateSettingsResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "update_settings",
+        (SettingsUpdate {
+            commission_rate_bps: Some(10_001),
+            ..empty_settings_update()
+        },),
+    )
+    .unwrap();
+    assert!(matches!(
+        result.0,
+        UpdateSettingsResult::Err(Error::InvalidSettings(_))
+    ));
+
+    let result = call_candid_as::<_, (UpdateSettingsResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "update_settings",
+        (&update,),
+    )
+    .unwrap();
+    assert_eq!(UpdateSettingsResult::Ok, result.0);
+
+    match call_candid_as::<_, (GetSettingsResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "get_settings",
+        ((),),
+    )
+    .unwrap()
+    {
+        (GetSettingsResult::Ok(settings),) => {
+            assert_eq!(5, settings.max_apps_per_developer);
+            assert_eq!(500, settings.commission_rate_bps);
+            assert_eq!(0.05, settings.exchange_rate_tolerance);
+            assert_eq!(test_case.ledger_canister, settings.ledger_canister_id);
+        }
+        (GetSettingsResult::Err(e),) => panic!("canister call failed: {e:?}"),
+    }
+}
+
+#[test]
+fn test_developers_can_fund_escrow_with_approval() {
+    let test_case = TestCase::setup_with_registered_developer1();
+    let fund_escrow = || {
+        call_candid_as::<_, (FundEscrowResult,)>(
+            &test_case.pic,
+            test_case.mu_smart_contract,
+            RawEffectivePrincipal::None,
+            test_case.developer1,
+            "fund_escrow",
+            (Nat::from(1_000_000_u64),),
+        )
+        .unwrap()
+        .0
+    };
+
+    // Nothing can be pulled without an approval
+    assert!(matches!(
+        fund_escrow(),
+        FundEscrowResult::Err(Error::Internal(_))
+    ));
+
+    test_case.ledger_approve(
+        test_case.developer1,
+        test_case.mu_smart_contract,
+        Tokens::from_e8s(1_000_000) + DEFAULT_FEE,
+    );
+    assert!(matches!(fund_escrow(), FundEscrowResult::Ok(_)));
+
+    let developer_info = match call_candid_as::<_, (GetDeveloperResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "get_developer",
+        ((),),
+    )
+    .unwrap()
+    {
+        (GetDeveloperResult::Ok(i),) => i,
+        (GetDeveloperResult::Err(e),) => panic!("canister call failed: {e:?}"),
+    };
+    let escrow_account = AccountIdentifier::from_slice(&developer_info.escrow_account).unwrap();
+    assert_eq!(
+        Tokens::from_e8s(1_000_000),
+        test_case.ledger_balance_of(escrow_account)
+    );
+    assert_eq!(None, developer_info.standing_allowance);
+
+    // Developers can opt in to a standing allowance for cycles requests
+    let result = call_candid_as::<_, (SetStandingAllowanceResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "set_standing_allowance",
+        (Some(mu_smart_contract::Tokens { e8s: 500_000_000 }),),
+    )
+    .unwrap();
+    assert_eq!(SetStandingAllowanceResult::Ok, result.0);
+
+    match call_candid_as::<_, (GetDeveloperResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "get_developer",
+        ((),),
+    )
+    .unwrap()
+    {
+        (GetDeveloperResult::Ok(i),) => assert_eq!(
+            Some(mu_smart_contract::Tokens { e8s: 500_000_000 }),
+            i.standing_allowance
+        ),
+        (GetDeveloperResult::Err(e),) => panic!("canister call failed: {e:?}"),
+    }
+}
+
+#[test]
+fn test_cycles_requests_are_covered_by_the_standing_allowance() {
+    let test_case = TestCase::setup_with_registered_developer1();
+    let escrow_account = test_case.escrow_account_of(test_case.developer1);
+    test_case
+        .ledger_transfer(
+            test_case.developer1,
+            None,
+            escrow_account,
+            Tokens::from_e8s(1_000_000_000),
+        )
+        .unwrap();
+    let app_id = match test_case.deploy_app(test_case.developer1, "TestApp") {
+        Result_::Ok(app_id) => app_id,
+        Result_::Err(e) => panic!("canister call failed: {e:?}"),
+    };
+
+    // The escrow account is emptied
+    let developer1_account = AccountIdentifier::new(&test_case.developer1, &DEFAULT_SUBACCOUNT);
+    let escrow_balance = test_case.ledger_balance_of(escrow_account);
+    match call_candid_as::<_, (RequestEscrowWithdrawResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "request_escrow_withdraw",
+        (
+            developer1_account,
+            mu_smart_contract::Tokens {
+                e8s: escrow_balance.e8s() - DEFAULT_FEE.e8s(),
+            },
+        ),
+    )
+    .unwrap()
+    {
+        (RequestEscrowWithdrawResult::Ok(_),) => {}
+        (RequestEscrowWithdrawResult::Err(e),) => panic!("canister call failed: {e:?}"),
+    };
+    assert_eq!(
+        Tokens::from_e8s(0),
+        test_case.ledger_balance_of(escrow_account)
+    );
+
+    test_case.ledger_approve(
+        test_case.developer1,
+        test_case.mu_smart_contract,
+        Tokens::from_e8s(100_000_000),
+    );
+    let result = call_candid_as::<_, (SetStandingAllowanceResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "set_standing_allowance",
+        (Some(mu_smart_contract::Tokens { e8s: 100_000_000 }),),
+    )
+    .unwrap();
+    assert_eq!(SetStandingAllowanceResult::Ok, result.0);
+
+    let quote = match call_candid_as::<_, (QuoteCyclesResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        random_principal(),
+        "quote_cycles",
+        (200_000_000_000_u64,),
+    )
+    .unwrap()
+    {
+        (QuoteCyclesResult::Ok(quote),) => quote,
+        (QuoteCyclesResult::Err(e),) => panic!("canister call failed: {e:?}"),
+    };
+
+    // The request pulls what the escrow account is missing from the developer's account
+    let result = call_candid_as::<_, (RequestCyclesResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        app_id,
+        "request_cycles",
+        (200_000_000_000_u64,),
+    )
+    .unwrap();
+    assert!(matches!(result.0, RequestCyclesResult::Ok(_)));
+    assert_eq!(
+        Tokens::from_e8s(0),
+        test_case.ledger_balance_of(escrow_account)
+    );
+
+    // What is pulled, and the fee of the pull, are taken off the allowance
+    match call_candid_as::<_, (GetDeveloperResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "get_developer",
+        ((),),
+    )
+    .unwrap()
+    {
+        (GetDeveloperResult::Ok(i),) => assert_eq!(
+            Some(mu_smart_contract::Tokens {
+                e8s: 100_000_000 - quote.total.e8s - DEFAULT_FEE.e8s()
+            }),
+            i.standing_allowance
+        ),
+        (GetDeveloperResult::Err(e),) => panic!("canister call failed: {e:?}"),
+    }
+}
+
+#[test]
+fn test_withdrawals_with_idempotency_key_are_executed_once() {
+    let test_case = TestCase::setup_with_registered_developer1();
+    let developer_info = match call_candid_as::<_, (GetDeveloperResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "get_developer",
+        ((),),
+    )
+    .unwrap()
+    {
+        (GetDeveloperResult::Ok(i),) => i,
+        (GetDeveloperResult::Err(e),) => panic!("canister call failed: {e:?}"),
+    };
+    let escrow_account = AccountIdentifier::from_slice(&developer_info.escrow_account).unwrap();
+    test_case
+        .ledger_transfer(
+            test_case.developer1,
+            None,
+            escrow_account,
+            Tokens::from_e8s(1_000_000),
+        )
+        .unwrap();
+
+    let developer1_account = AccountIdentifier::new(&test_case.developer1, &DEFAULT_SUBACCOUNT);
+    let withdraw =
+        |idempotency_key: Option<&str>| match call_candid_as::<_, (RequestEscrowWithdrawResult,)>(
+            &test_case.pic,
+            test_case.mu_smart_contract,
+            RawEffectivePrincipal::None,
+            test_case.developer1,
+            "request_escrow_withdraw",
+            (
+                developer1_account,
+                mu_smart_contract::Tokens { e8s: 100_000 },
+                idempotency_key,
+            ),
+        )
+        .unwrap()
+        {
+            (RequestEscrowWithdrawResult::Ok(i),) => i,
+            (RequestEscrowWithdrawResult::Err(e),) => panic!("canister call failed: {e:?}"),
+        };
+
+    // Retries with the same key return the original block
+    let block_index = withdraw(Some("withdraw-1"));
+    assert_eq!(block_index, withdraw(Some("withdraw-1")));
+    assert_eq!(
+        Tokens::from_e8s(1_000_000 - 100_000) - DEFAULT_FEE,
+        test_case.ledger_balance_of(escrow_account)
+    );
+
+    // Reusing a key for another amount is rejected
+    let result = call_candid_as::<_, (RequestEscrowWithdrawResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "request_escrow_withdraw",
+        (
+            developer1_account,
+            mu_smart_contract::Tokens { e8s: 200_000 },
+            Some("withdraw-1"),
+        ),
+    )
+    .unwrap();
+    assert_eq!(
+        RequestEscrowWithdrawResult::Err(Error::IdempotencyKeyConflict),
+        result.0
+    );
+
+    // Other keys, or no key at all, are new withdrawals
+    assert_ne!(block_index, withdraw(Some("withdraw-2")));
+    assert_ne!(block_index, withdraw(None));
+    assert_eq!(
+        Tokens::from_e8s(1_000_000 - 3 * 100_000) - DEFAULT_FEE - DEFAULT_FEE - DEFAULT_FEE,
+        test_case.ledger_balance_of(escrow_account)
+    );
+}
+
+#[test]
+fn test_developers_can_list_their_pending_top_ups() {
+    let test_case = TestCase::setup_with_registered_developer1();
+
+    let result = call_candid_as::<_, (GetPendingTopUpsResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        random_principal(),
+        "get_pending_top_ups",
+        ((),),
+    )
+    .unwrap();
+    assert_eq!(
+        GetPendingTopUpsResult::Err(Error::DeveloperAccountNotFound),
+        result.0
+    );
+
+    // Nothing was paid to the cycles minting canister yet
+    let result = call_candid_as::<_, (GetPendingTopUpsResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "get_pending_top_ups",
+        ((),),
+    )
+    .unwrap();
+    assert_eq!(GetPendingTopUpsResult::Ok(Vec::new()), result.0);
+}
+
+#[test]
+fn test_concurrent_withdrawals_of_a_developer_are_rejected() {
+    let test_case = TestCase::setup_with_registered_developer1();
+    let developer_info = match call_candid_as::<_, (GetDeveloperResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        test_case.developer1,
+        "get_developer",
+        ((),),
+    )
+    .unwrap()
+    {
+        (GetDeveloperResult::Ok(i),) => i,
+        (GetDeveloperResult::Err(e),) => panic!("canister call failed: {e:?}"),
+    };
+    let escrow_account = AccountIdentifier::from_slice(&developer_info.escrow_account).unwrap();
+    test_case
+        .ledger_transfer(
+            test_case.developer1,
+            None,
+            escrow_account,
+            Tokens::from_e8s(1_000_000),
+        )
+        .unwrap();
+
+    // Both withdrawals are submitted before any of them reaches the ledger
+    let developer1_account = AccountIdentifier::new(&test_case.developer1, &DEFAULT_SUBACCOUNT);
+    let message_ids: Vec<_> = (0..2)
+        .map(|_| {
+            test_case
+                .pic
+                .submit_call(
+                    test_case.mu_smart_contract,
+                    test_case.developer1,
+                    "request_escrow_withdraw",
+                    Encode!(
+                        &developer1_account,
+                        &mu_smart_contract::Tokens { e8s: 100_000 },
+                        &None::<String>
+                    )
+                    .unwrap(),
+                )
+                .unwrap()
+        })
+        .collect();
+    let results: Vec<_> = message_ids
+        .into_iter()
+        .map(
+            |message_id| match test_case.pic.await_call(message_id).unwrap() {
+                WasmResult::Reply(reply) => {
+                    candid::decode_one::<RequestEscrowWithdrawResult>(&reply).unwrap()
+                }
+                WasmResult::Reject(e) => panic!("canister call rejected: {e}"),
+            },
+        )
+        .collect();
+
+    assert!(matches!(results[0], RequestEscrowWithdrawResult::Ok(_)));
+    assert_eq!(
+        RequestEscrowWithdrawResult::Err(Error::OperationInProgress),
+        results[1]
+    );
+    assert_eq!(
+        Tokens::from_e8s(1_000_000 - 100_000) - DEFAULT_FEE,
+        test_case.ledger_balance_of(escrow_account)
+    );
+}
+
+#[test]
+fn test_icp_cycles_rate_is_public() {
+    let test_case = TestCase::setup();
+    // Let the refresh timer fetch the rate
+    for _ in 0..10 {
+        test_case.pic.tick();
+    }
+
+    let result = call_candid_as::<_, (Option<IcpCyclesRate>,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        random_principal(),
+        "get_icp_cycles_rate",
+        ((),),
+    )
+    .unwrap();
+    match result.0 {
+        Some(IcpCyclesRate { rate, decimals, .. }) => {
+            assert_eq!(4_000_000_000_000, rate);
+            assert_eq!(0, decimals);
+        }
+        None => panic!("the exchange rate was not fetched"),
+    }
+
+    // There is no rate until it can be fetched
+    let test_case = TestCase::setup_without_rate_sources();
+    let result = call_candid_as::<_, (Option<IcpCyclesRate>,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        random_principal(),
+        "get_icp_cycles_rate",
+        ((),),
+    )
+    .unwrap();
+    assert_eq!(None, result.0);
+}
+
+#[test]
+fn test_quotes_need_an_exchange_rate() {
+    let test_case = TestCase::setup_without_rate_sources();
+
+    let result = call_candid_as::<_, (QuoteCyclesResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        random_principal(),
+        "quote_cycles",
+        (1_000_000_000_000_u64,),
+    )
+    .unwrap();
+    assert_eq!(
+        QuoteCyclesResult::Err(Error::ExchangeRateUnavailable),
+        result.0
+    );
+
+    let result = call_candid_as::<_, (QuoteDeployResult,)>(
+        &test_case.pic,
+        test_case.mu_smart_contract,
+        RawEffectivePrincipal::None,
+        random_principal(),
+        "quote_deploy",
+        ((),),
+    )
+    .unwrap();
+    assert_eq!(
+        QuoteDeployResult::Err(Error::ExchangeRateUnavailable),
+        result.0
+    );
+}
+
+#[test]
+fn test_quotes_are_not_given_from_a_stale_exchange_rate() {
+    let test_case = TestCase::setup();
+    // Let the refresh timer fetch the rate
+    for _ in 0..10 {
+        test_case.pic.tick();
+    }
+    let quote = || {
+        call_candid_as::<_, (QuoteCyclesResult,)>(
+            &test_case.pic,
+            test_case.mu_smart_contract,
+            RawEffectivePrincipal::None,
+            random_principal(),
+            "quote_cycles",
+            (1_000_000_000_000_u64,),
+        )
+        .unwrap()
+        .0
+    };
+    assert!(matches!(quote(), QuoteCyclesResult::Ok(_)));
+
+    // The rate can not be renewed anymore, and gets older than an hour
+    test_case
+        .pic
+        .stop_canister(MAINNET_CYCLES_MINTING_CANISTER_ID, None)
+        .unwrap();
+    test_case.pic.advance_time(Duration::from_secs(2 * 60 * 60));
+    for _ in 0..10 {
+        test_case.pic.tick();
+    }
+    assert_eq!(
+        QuoteCyclesResult::Err(Error::ExchangeRateUnavailable),
+        quote()
+    );
+}
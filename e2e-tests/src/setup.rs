@@ -21,23 +21,30 @@ use ic_ledger_types::TransferArgs;
 use ic_ledger_types::TransferResult;
 use ic_ledger_types::DEFAULT_FEE;
 use ic_ledger_types::DEFAULT_SUBACCOUNT;
+use ic_ledger_types::MAINNET_CYCLES_MINTING_CANISTER_ID;
 use pocket_ic::call_candid_as;
 use pocket_ic::common::rest::RawEffectivePrincipal;
 use pocket_ic::common::rest::SubnetId;
+use pocket_ic::CallError;
 use pocket_ic::CanisterSettings;
 use pocket_ic::WasmResult;
 use pocket_ic::{PocketIc, PocketIcBuilder};
 use serde::Deserialize;
 use serde::Serialize;
+use serde_bytes::ByteBuf;
 
 use crate::declarations::ledger_canister;
 use crate::declarations::mu_smart_contract;
 use crate::declarations::mu_smart_contract::Result_;
+use crate::utils::app_package;
 use crate::utils::random_principal;
+use crate::utils::test_wasm_module;
 
-const MU_SMART_CONTRACT_WASM_FILE: OnceLock<Vec<u8>> = OnceLock::new();
+static MU_SMART_CONTRACT_WASM_FILE: OnceLock<Vec<u8>> = OnceLock::new();
 // 2T cycles
 const INIT_CYCLES: u128 = 2_000_000_000_000;
+// 100T cycles, minted by the cycles minting canister stub for the top-ups.
+const NNS_STUB_CYCLES: u128 = 100_000_000_000_000;
 
 pub fn canister_wasm_file(name: &str, target: &str) -> Vec<u8> {
     let subpath_to_wasm_module = format!("wasm32-unknown-unknown/{target}/{name}.wasm");
@@ -63,10 +70,23 @@ pub struct TestCase {
     pub mu_smart_contract: Principal,
     pub ledger_canister: Principal,
     pub developer1: Principal,
+    pub manager: Principal,
+    pub treasury_account: AccountIdentifier,
 }
 
 impl TestCase {
+    /// Setup with a stub standing for both the cycles minting and the exchange rate canisters,
+    /// see `nns_stub`.
     pub fn setup() -> Self {
+        Self::setup_with_rate_sources(true)
+    }
+
+    /// Setup without the cycles minting and exchange rate canisters, so there is no exchange rate.
+    pub fn setup_without_rate_sources() -> Self {
+        Self::setup_with_rate_sources(false)
+    }
+
+    fn setup_with_rate_sources(rate_sources: bool) -> Self {
         let pic = PocketIcBuilder::new()
             .with_nns_subnet()
             .with_application_subnet()
@@ -122,7 +142,7 @@ impl TestCase {
             max_message_size_bytes: None,
             icrc1_minting_account: None,
             archive_options: None,
-            feature_flags: None,
+            feature_flags: Some(ledger_canister::FeatureFlags { icrc2: true }),
         };
 
         // Install ledger canister.
@@ -133,14 +153,53 @@ impl TestCase {
             None,
         );
 
+        let nns_stub = rate_sources.then(|| {
+            let nns_stub = create_canister_on_subnet_with_id(
+                &pic,
+                None,
+                None,
+                nns_subnet,
+                MAINNET_CYCLES_MINTING_CANISTER_ID,
+            );
+            pic.add_cycles(nns_stub, NNS_STUB_CYCLES);
+            pic.install_canister(
+                nns_stub,
+                canister_wasm_file("nns_stub", "canister-release"),
+                Vec::new(),
+                None,
+            );
+            nns_stub
+        });
+
         let mu_smart_contract = pic.create_canister_on_subnet(None, None, app_subnet);
         pic.add_cycles(mu_smart_contract, INIT_CYCLES);
 
+        let manager = random_principal();
+        let treasury_account = AccountIdentifier::new(&random_principal(), &DEFAULT_SUBACCOUNT);
+
         let args = Encode!(&mu_smart_contract::InitArgs {
             minimum_escrow_balance_for_deploy: mu_smart_contract::Tokens { e8s: 1_000_000_000 },
             max_apps_per_developer: 2,
-            commition_rate: 0.05,
+            commission_rate_bps: 500,
+            treasury_account: ByteBuf::from(treasury_account.as_ref()),
             exchange_rate_timeout_seconds: 10,
+            exchange_rate_tolerance: None,
+            max_wasm_module_size: 10 * 1024 * 1024,
+            cycles_request_threshold: 100_000_000_000,
+            cycles_request_amount: 200_000_000_000,
+            max_cycles_per_day: 1_000_000_000_000,
+            cycles_request_cooldown_seconds: 5 * 60,
+            manager_canisters: vec![manager],
+            service_prices: mu_smart_contract::ServicePrices {
+                kv_store_reads_per_million: mu_smart_contract::Tokens { e8s: 10_000 },
+                kv_store_writes_per_million: mu_smart_contract::Tokens { e8s: 100_000 },
+                kv_store_storage_per_gib: mu_smart_contract::Tokens { e8s: 1_000_000 },
+                blob_storage_storage_per_gib: mu_smart_contract::Tokens { e8s: 500_000 },
+                blob_storage_download_per_gib: mu_smart_contract::Tokens { e8s: 1_000_000 },
+            },
+            ledger_canister_id: Some(ledger_canister),
+            cycles_minting_canister_id: nns_stub,
+            exchange_rate_canister_id: nns_stub,
         })
         .unwrap();
 
@@ -151,6 +210,8 @@ impl TestCase {
             mu_smart_contract,
             ledger_canister,
             developer1,
+            manager,
+            treasury_account,
         }
     }
 
@@ -170,6 +231,98 @@ impl TestCase {
         test_case
     }
 
+    /// Legacy escrow account of a registered developer.
+    pub fn escrow_account_of(&self, developer: Principal) -> AccountIdentifier {
+        match call_candid_as::<_, (mu_smart_contract::GetDeveloperResult,)>(
+            &self.pic,
+            self.mu_smart_contract,
+            RawEffectivePrincipal::None,
+            developer,
+            "get_developer",
+            ((),),
+        )
+        .unwrap()
+        {
+            (mu_smart_contract::GetDeveloperResult::Ok(i),) => {
+                AccountIdentifier::from_slice(&i.escrow_account).unwrap()
+            }
+            (mu_smart_contract::GetDeveloperResult::Err(e),) => {
+                panic!("canister call failed: {e:?}")
+            }
+        }
+    }
+
+    /// Deploy the test wasm module as an app of `developer`.
+    pub fn deploy_app(&self, developer: Principal, name: &str) -> Result_ {
+        call_candid_as::<_, (Result_,)>(
+            &self.pic,
+            self.mu_smart_contract,
+            RawEffectivePrincipal::None,
+            developer,
+            "deploy_app",
+            (mu_smart_contract::DeployAppRequest {
+                name: name.to_string(),
+                app_data: mu_smart_contract::AppData::Inline(ByteBuf::from(app_package(
+                    name,
+                    "0.1.0",
+                    &test_wasm_module(),
+                ))),
+            },),
+        )
+        .unwrap()
+        .0
+    }
+
+    /// Fund the escrow account of `developer` for a deploy, and deploy the test wasm module as
+    /// one of their apps.
+    pub fn deploy_funded_app(&self, developer: Principal, name: &str) -> Principal {
+        let escrow_account = self.escrow_account_of(developer);
+        self.ledger_transfer(
+            developer,
+            None,
+            escrow_account,
+            Tokens::from_e8s(1_000_000_000),
+        )
+        .unwrap();
+        match self.deploy_app(developer, name) {
+            Result_::Ok(app_id) => app_id,
+            Result_::Err(e) => panic!("canister call failed: {e:?}"),
+        }
+    }
+
+    pub fn upgrade_mu_smart_contract(&self, args: Vec<u8>) {
+        self.try_upgrade_mu_smart_contract(args).unwrap();
+    }
+
+    pub fn try_upgrade_mu_smart_contract(&self, args: Vec<u8>) -> Result<(), CallError> {
+        self.pic.upgrade_canister(
+            self.mu_smart_contract,
+            mu_smart_contract_wasm_file(),
+            args,
+            None,
+        )
+    }
+
+    /// Reinstall the canister with its first version, built by `make build-mu_smart_contract-baseline`.
+    pub fn reinstall_mu_smart_contract_baseline(&self) {
+        let args = Encode!(&BaselineInitArgs {
+            minimum_escrow_balance_for_deploy: Tokens::from_e8s(1_000_000_000),
+            max_apps_per_developer: 2,
+            commition_rate: 0.05,
+            exchange_rate_timeout_seconds: 10,
+        })
+        .unwrap();
+
+        self.pic
+            .reinstall_canister(
+                self.mu_smart_contract,
+                canister_wasm_file("mu_smart_contract_baseline", "canister-release"),
+                args,
+                None,
+            )
+            .unwrap();
+    }
+
     pub fn ledger_balance_of(&self, account: AccountIdentifier) -> Tokens {
         let args = encode_one(AccountBalanceArgs { account }).unwrap();
         let result = self
@@ -187,6 +340,35 @@ impl TestCase {
         }
     }
 
+    pub fn ledger_approve(&self, caller: Principal, spender: Principal, amount: Tokens) {
+        let args = ledger_canister::ApproveArgs {
+            fee: None,
+            memo: None,
+            from_subaccount: None,
+            created_at_time: None,
+            amount: Nat::from(amount.e8s()),
+            expected_allowance: None,
+            expires_at: None,
+            spender: ledger_canister::Account {
+                owner: spender,
+                subaccount: None,
+            },
+        };
+
+        let (result,) = call_candid_as::<_, (ledger_canister::ApproveResult,)>(
+            &self.pic,
+            self.ledger_canister,
+            RawEffectivePrincipal::None,
+            caller,
+            "icrc2_approve",
+            (args,),
+        )
+        .unwrap();
+        if let ledger_canister::ApproveResult::Err(e) = result {
+            panic!("approve failed: {e:?}");
+        }
+    }
+
     pub fn ledger_transfer(
         &self,
         caller: Principal,
@@ -239,6 +421,15 @@ pub fn create_canister_on_subnet_with_id(
     canister_id
 }
 
+// Init arguments of the first version of the canister.
+#[derive(CandidType)]
+struct BaselineInitArgs {
+    minimum_escrow_balance_for_deploy: Tokens,
+    max_apps_per_developer: u64,
+    commition_rate: f32,
+    exchange_rate_timeout_seconds: u64,
+}
+
 #[derive(
     CandidType, Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Default,
 )]
@@ -1,8 +1,92 @@
 use candid::Principal;
 use rand::RngCore;
+use serde::Serialize;
+
+use crate::declarations::mu_smart_contract::SettingsUpdate;
 
 pub fn random_principal() -> Principal {
     let random_bytes = rand::thread_rng().next_u32().to_ne_bytes();
 
     Principal::from_slice(&random_bytes)
 }
+
+/// Smallest wasm module accepted as an app, exporting an empty `ping` query method.
+pub fn test_wasm_module() -> Vec<u8> {
+    let mut module = b"\0asm\x01\0\0\0".to_vec();
+    // Type section, a single `() -> ()` function type
+    module.extend_from_slice(&[0x01, 0x04, 0x01, 0x60, 0x00, 0x00]);
+    // Function section, a single function of type 0
+    module.extend_from_slice(&[0x03, 0x02, 0x01, 0x00]);
+    // Export section, function 0 exported as `canister_query ping`
+    module.extend_from_slice(&[0x07, 0x17, 0x01, 0x13]);
+    module.extend_from_slice(b"canister_query ping");
+    module.extend_from_slice(&[0x00, 0x00]);
+    // Code section, an empty function body
+    module.extend_from_slice(&[0x0a, 0x04, 0x01, 0x02, 0x00, 0x0b]);
+    module
+}
+
+/// The test wasm module, with a `canister_init` that traps so it can not be installed.
+pub fn trapping_init_wasm_module() -> Vec<u8> {
+    let mut module = b"\0asm\x01\0\0\0".to_vec();
+    // Type section, a single `() -> ()` function type
+    module.extend_from_slice(&[0x01, 0x04, 0x01, 0x60, 0x00, 0x00]);
+    // Function section, two functions of type 0
+    module.extend_from_slice(&[0x03, 0x03, 0x02, 0x00, 0x00]);
+    // Export section, function 0 exported as `canister_query ping` and 1 as `canister_init`
+    module.extend_from_slice(&[0x07, 0x27, 0x02, 0x13]);
+    module.extend_from_slice(b"canister_query ping");
+    module.extend_from_slice(&[0x00, 0x00, 0x0d]);
+    module.extend_from_slice(b"canister_init");
+    module.extend_from_slice(&[0x00, 0x01]);
+    // Code section, an empty function body and one that only traps
+    module.extend_from_slice(&[0x0a, 0x08, 0x02, 0x02, 0x00, 0x0b, 0x03, 0x00, 0x00, 0x0b]);
+    module
+}
+
+#[derive(Serialize)]
+struct AppPackage<'a> {
+    manifest: Manifest<'a>,
+    #[serde(with = "serde_bytes")]
+    wasm_module: &'a [u8],
+}
+
+#[derive(Serialize)]
+struct Manifest<'a> {
+    name: &'a str,
+    version: &'a str,
+}
+
+/// CBOR encoded app package, the same way the mu CLI builds it.
+pub fn app_package(name: &str, version: &str, wasm_module: &[u8]) -> Vec<u8> {
+    let package = AppPackage {
+        manifest: Manifest { name, version },
+        wasm_module,
+    };
+
+    let mut bytes = Vec::new();
+    ciborium::into_writer(&package, &mut bytes).unwrap();
+    bytes
+}
+
+/// Settings update that leaves all the settings unchanged.
+pub fn empty_settings_update() -> SettingsUpdate {
+    SettingsUpdate {
+        minimum_escrow_balance_for_deploy: None,
+        max_apps_per_developer: None,
+        commission_rate_bps: None,
+        treasury_account: None,
+        exchange_rate_timeout_seconds: None,
+        exchange_rate_tolerance: None,
+        max_wasm_module_size: None,
+        cycles_request_threshold: None,
+        cycles_request_amount: None,
+        max_cycles_per_day: None,
+        cycles_request_cooldown_seconds: None,
+        manager_canisters: None,
+        service_prices: None,
+        ledger_canister_id: None,
+        cycles_minting_canister_id: None,
+        exchange_rate_canister_id: None,
+    }
+}
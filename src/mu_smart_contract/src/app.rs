@@ -1,46 +1,87 @@
-use std::borrow::Cow;
+use std::time::Duration;
 
 use candid::CandidType;
-use candid::Decode;
 use candid::Deserialize;
-use candid::Encode;
 use candid::Principal;
-use ic_cdk::api::management_canister::main::raw_rand;
+use ic_cdk::api::management_canister::main::CanisterInstallMode;
 use ic_ledger_types::Timestamp;
 use ic_ledger_types::Tokens;
-use ic_stable_structures::storable::Bound;
-use ic_stable_structures::Storable;
-use serde_bytes::ByteBuf;
+use ic_ledger_types::DEFAULT_FEE;
 
+use crate::app::budget::AppBudget;
+use crate::app::budget::BudgetSpending;
+use crate::app::cycles_policy::CyclesPolicy;
+use crate::app::cycles_policy::CyclesRequests;
+use crate::app::package::AppPackage;
+use crate::app::package::Manifest;
+use crate::commission::charge_commission;
+use crate::commission::commission_of;
 use crate::developer::Developer;
 use crate::developer::DeveloperID;
 use crate::error::Error;
+use crate::guard::OperationGuard;
 use crate::memory::STATE;
+use crate::schema;
+use crate::schema::decode;
+use crate::schema::Versioned;
+use crate::upload::UploadID;
+use crate::usage::ServiceUsage;
+use crate::utils::exchange::icp_needed_for_cycles;
 use crate::utils::exchange::top_up_canister;
+use crate::utils::management::create_app_canister;
+use crate::utils::management::has_app_code;
+use crate::utils::management::install_app_code;
 use crate::Result;
 
+pub mod budget;
+pub mod cycles_controller;
+pub mod cycles_policy;
+pub mod package;
+pub mod wasm;
+
 #[derive(CandidType, Deserialize, Clone)]
 pub enum UsageKind {
     CyclesCharge { cylces: u128 },
-    AdditionalServices { details: ByteBuf }, // Will be filled in future
+    AdditionalServices { usage: ServiceUsage },
 }
 
 #[derive(CandidType, Deserialize)]
 pub struct AppUsage {
-    kind: UsageKind,
-    timestamp: Timestamp,
-    amount: Tokens,
-    is_paid: bool,
+    pub kind: UsageKind,
+    pub timestamp: Timestamp,
+    pub amount: Tokens,
+    // Platform fee charged on top of `amount`.
+    pub commission: Tokens,
+    pub is_paid: bool,
 }
 
 pub type AppID = Principal;
 
+impl Versioned for AppUsage {
+    const NAME: &'static str = "app usage";
+    const VERSION: u8 = 1;
+}
+
+const DEFAULT_APPS_PAGE_SIZE: u64 = 50;
+const MAX_APPS_PAGE_SIZE: u64 = 100;
+const DEFAULT_USAGES_PAGE_SIZE: u64 = 100;
+const MAX_USAGES_PAGE_SIZE: u64 = 1_000;
+
+// Covers the canister creation fee and leaves the new canister with an initial balance.
+const APP_CANISTER_CREATION_CYCLES: u64 = 500_000_000_000;
+
+// Instructions a batch of the usages migration stops at, well below the limit of a message.
+const USAGES_MIGRATION_BATCH_INSTRUCTIONS: u64 = 1_000_000_000;
+
 #[derive(CandidType, Deserialize)]
 pub struct App {
     // I know this is not good, but we need a way to link back this app to the developer.
     pub developer_id: DeveloperID,
     pub state: AppState,
-    pub usages: Vec<AppUsage>,
+    pub cycles_policy: CyclesPolicy,
+    pub cycles_requests: CyclesRequests,
+    pub budget: Option<AppBudget>,
+    pub budget_spending: BudgetSpending,
 }
 
 impl App {
@@ -49,20 +90,22 @@ impl App {
             AppState::Active(ref app) => dto::AppState::Active {
                 revision: app.revision,
                 name: app.name.clone(),
+                manifest: app.manifest.clone(),
+                previous_revisions: app.previous_revisions.clone(),
             },
             AppState::Deleted => dto::AppState::Deleted,
         };
 
-        let usages = self
-            .usages
-            .iter()
-            .map(|u| dto::AppUsage {
-                kind: u.kind.clone(),
-                timestamp: u.timestamp,
-                amount: u.amount,
-            })
-            .collect();
-        dto::AppDto { id, state, usages }
+        dto::AppDto {
+            id,
+            state,
+            cycles_policy: self.cycles_policy.clone(),
+            budget: self.budget.clone(),
+            budget_spent: self
+                .budget
+                .as_ref()
+                .map(|budget| self.budget_spending.spent(budget, ic_cdk::api::time())),
+        }
     }
 }
 
@@ -76,19 +119,64 @@ pub enum AppState {
 pub struct ActiveApp {
     pub revision: u32,
     pub name: String,
+    pub manifest: Manifest,
+    // The wasm module of the app.
     pub data: Vec<u8>,
+    pub deployed_at: Timestamp,
+    pub previous_revisions: Vec<AppRevision>,
 }
 
-impl Storable for App {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+impl ActiveApp {
+    /// Replace the app code with a new revision, keeping the metadata of the current one.
+    pub fn upgrade(&mut self, name: String, package: AppPackage, deployed_at: Timestamp) -> u32 {
+        self.previous_revisions.push(AppRevision {
+            revision: self.revision,
+            name: std::mem::replace(&mut self.name, name),
+            version: std::mem::replace(&mut self.manifest, package.manifest).version,
+            deployed_at: self.deployed_at,
+        });
+        self.revision += 1;
+        self.data = package.wasm_module;
+        self.deployed_at = deployed_at;
+        self.revision
     }
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct AppRevision {
+    pub revision: u32,
+    pub name: String,
+    pub version: String,
+    pub deployed_at: Timestamp,
+}
 
-    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+impl Versioned for App {
+    const NAME: &'static str = "app";
+    const VERSION: u8 = 2;
+
+    fn migrate(version: u8, payload: &[u8]) -> Result<Self> {
+        match version {
+            1 => decode::<Self, schema::v1::App>(payload).map(Self::from),
+            _ => Err(Error::Internal(format!(
+                "Unknown app record version {version}"
+            ))),
+        }
     }
+}
 
-    const BOUND: Bound = Bound::Unbounded;
+/// Move the usages kept in the app records by previous versions of the canister to their own map,
+/// in batches of one message each, from `start`.
+pub fn start_app_usages_migration(start: Option<AppID>) {
+    ic_cdk_timers::set_timer(Duration::ZERO, move || {
+        let next = STATE.with_borrow_mut(|s| {
+            s.migrate_app_usages(start, || {
+                ic_cdk::api::instruction_counter() > USAGES_MIGRATION_BATCH_INSTRUCTIONS
+            })
+        });
+        if next.is_some() {
+            start_app_usages_migration(next);
+        }
+    });
 }
 
 #[ic_cdk::query]
@@ -100,67 +188,253 @@ fn get_app(app_id: crate::app::AppID) -> Result<Option<crate::app::dto::AppDto>>
     })
 }
 
+/// Apps of the caller by their ID, in pages of at most `limit` apps.
 #[ic_cdk::query]
-fn get_apps() -> Result<Vec<crate::app::dto::AppDto>> {
+fn get_apps(
+    cursor: Option<crate::app::AppID>,
+    limit: Option<u64>,
+) -> Result<crate::app::dto::AppsPage> {
+    let limit = limit
+        .unwrap_or(DEFAULT_APPS_PAGE_SIZE)
+        .clamp(1, MAX_APPS_PAGE_SIZE);
     let (developer_id, _) = Developer::get_caller_developer_account()?;
+    let (apps, next) =
+        STATE.with_borrow(|s| s.get_apps_of_developer(&developer_id, cursor, limit as usize))?;
+    Ok(dto::AppsPage {
+        apps: apps
+            .into_iter()
+            .map(|(app_id, app)| app.as_dto(app_id))
+            .collect(),
+        next,
+    })
+}
+
+/// Usages of an app, oldest first, in pages of at most `limit` usages.
+#[ic_cdk::query]
+fn get_app_usages(
+    request: crate::app::dto::AppUsagesRequest,
+) -> Result<crate::app::dto::AppUsagesPage> {
+    let (developer_id, _) = Developer::get_caller_developer_account()?;
+    let limit = request
+        .limit
+        .unwrap_or(DEFAULT_USAGES_PAGE_SIZE)
+        .clamp(1, MAX_USAGES_PAGE_SIZE);
+    let start = match request.cursor {
+        Some(cursor) => (cursor.timestamp.timestamp_nanos, cursor.seq),
+        None => (request.from.map_or(0, |t| t.timestamp_nanos), 0),
+    };
+    let end = request.to.map_or(u64::MAX, |t| t.timestamp_nanos);
+
     STATE.with_borrow(|s| {
-        s.get_apps_of_developer(&developer_id).map(|i| {
-            i.into_iter()
-                .map(|(app_id, app)| app.as_dto(app_id))
-                .collect()
+        if s.get_app_of_developer(&developer_id, &request.app_id)?
+            .is_none()
+        {
+            return Err(Error::AppNotFound);
+        }
+        let (usages, next) = s.get_app_usages(request.app_id, start, end, limit as usize)?;
+        Ok(dto::AppUsagesPage {
+            usages: usages.into_iter().map(dto::AppUsage::from).collect(),
+            next: next.map(|(timestamp_nanos, seq)| dto::UsageCursor {
+                timestamp: Timestamp { timestamp_nanos },
+                seq,
+            }),
         })
     })
 }
 
-// Note: Will not deploy, just upload for now.
 #[ic_cdk::update]
 async fn deploy_app(request: crate::app::dto::DeployAppRequest) -> Result<crate::app::AppID> {
     let (developer_id, developer) = Developer::get_caller_developer_account()?;
+    let _guard = OperationGuard::new(developer_id)?;
     developer
         .ensure_developer_escorw_has_minimum_balance_for_deploy()
         .await?;
-    developer.ensure_developer_has_budget_for_new_app()?;
+    Developer::ensure_developer_has_budget_for_new_app(&developer_id)?;
+    let package = AppPackage::decode(&request.app_data.load(&developer_id)?)?;
+    let wasm_module = package.build_wasm_module()?;
 
-    // TODO: Check if there is already another app deployed with this id and regenerate or change
-    // it if needed.
-    let app_id = {
-        let rand_bytes = raw_rand().await.map_err(|(_, s)| Error::Internal(s))?.0;
-        Principal::from_slice(&rand_bytes[0..29])
+    // Nothing is spent before the package is checked. The cycles left by a deploy that could not
+    // create its canister are spent first, the escrow tokens are converted into cycles for this
+    // canister only for the rest.
+    let credit = STATE
+        .with_borrow(|s| s.get_deploy_credit(&developer_id))?
+        .unwrap_or_default();
+    let missing = (APP_CANISTER_CREATION_CYCLES as u128).saturating_sub(credit.cycles) as u64;
+    let (cycles_topped_up, icp_tokens_topped_up) = if missing > 0 {
+        top_up_canister(developer_id, developer.escrow_account, None, missing).await?
+    } else {
+        (0, Tokens::from_e8s(0))
     };
 
+    let cycles = credit.cycles + cycles_topped_up;
+    let app_id = match create_app_canister(cycles).await {
+        Ok(app_id) => app_id,
+        Err(e) => {
+            // The cycles are refunded along with the rejected call, they are kept for the next
+            // deploy.
+            STATE.with_borrow_mut(|s| {
+                s.add_deploy_credit(developer_id, cycles_topped_up, icp_tokens_topped_up)
+            })?;
+            return Err(e);
+        }
+    };
+    STATE.with_borrow_mut(|s| s.spend_deploy_credit(developer_id, &credit));
+    let icp_tokens_used = credit.amount + icp_tokens_topped_up;
+    let commission =
+        charge_commission(developer_id, developer.escrow_account, icp_tokens_used).await;
+
+    let now = Timestamp {
+        timestamp_nanos: ic_cdk::api::time(),
+    };
     let app = App {
         developer_id,
         state: AppState::Active(ActiveApp {
             revision: 1,
             name: request.name,
-            data: request.app_data,
+            manifest: package.manifest,
+            data: package.wasm_module,
+            deployed_at: now,
+            previous_revisions: Vec::new(),
         }),
-        usages: Vec::new(),
+        cycles_policy: STATE.with_borrow(|s| CyclesPolicy::new(s.settings())),
+        cycles_requests: CyclesRequests::default(),
+        budget: None,
+        budget_spending: BudgetSpending::default(),
     };
 
-    STATE.with_borrow_mut(|s| s.register_app(app_id, app));
+    // The canister is already paid for, so it is registered before installing the code. If the
+    // installation fails, the app stays with the developer, and `upgrade_app` installs the code.
+    let usage = AppUsage {
+        kind: UsageKind::CyclesCharge { cylces: cycles },
+        timestamp: now,
+        amount: icp_tokens_used,
+        commission,
+        is_paid: true,
+    };
+    STATE.with_borrow_mut(|s| {
+        s.register_app(app_id, app)?;
+        s.register_usage(app_id, usage).map(|_| ())
+    })?;
+
+    install_app_code(app_id, wasm_module, CanisterInstallMode::Install).await?;
+    request.app_data.consume();
 
     Ok(app_id)
 }
 
-// Note: Will not undeploy, just remove for now.
+#[ic_cdk::update]
+async fn upgrade_app(
+    app_id: crate::app::AppID,
+    request: crate::app::dto::DeployAppRequest,
+) -> Result<u32> {
+    let (developer_id, _) = Developer::get_caller_developer_account()?;
+    // Uploads and apps of the developer are changed across the install, like in a deploy.
+    let _guard = OperationGuard::new(developer_id)?;
+    match STATE.with_borrow(|s| s.get_app_of_developer(&developer_id, &app_id))? {
+        Some(App {
+            state: AppState::Active(_),
+            ..
+        }) => (),
+        _ => return Err(Error::AppNotFound),
+    }
+    let package = AppPackage::decode(&request.app_data.load(&developer_id)?)?;
+    let wasm_module = package.build_wasm_module()?;
+
+    // The code of an app whose deploy failed to install it is installed from scratch.
+    let mode = if has_app_code(app_id).await? {
+        CanisterInstallMode::Upgrade(None)
+    } else {
+        CanisterInstallMode::Install
+    };
+    install_app_code(app_id, wasm_module, mode).await?;
+    request.app_data.consume();
+
+    let deployed_at = Timestamp {
+        timestamp_nanos: ic_cdk::api::time(),
+    };
+    STATE.with_borrow_mut(|s| s.upgrade_app(app_id, request.name, package, deployed_at))
+}
+
+// Note: Will not undeploy, just mark the app as deleted for now.
 #[ic_cdk::update]
 fn remove_app(app_id: crate::app::AppID) -> Result<()> {
-    let _ = Developer::get_caller_developer_account()?;
-    STATE.with_borrow_mut(|s| s.remove_app(app_id))
+    let (developer_id, _) = Developer::get_caller_developer_account()?;
+    STATE.with_borrow_mut(|s| {
+        if s.get_app_of_developer(&developer_id, &app_id)?.is_none() {
+            return Err(Error::AppNotFound);
+        }
+        s.remove_app(app_id)
+    })
+}
+
+#[ic_cdk::update]
+fn set_app_cycles_policy(
+    app_id: crate::app::AppID,
+    policy: crate::app::cycles_policy::CyclesPolicy,
+) -> Result<()> {
+    let (developer_id, _) = Developer::get_caller_developer_account()?;
+    STATE.with_borrow_mut(|s| {
+        if s.get_app_of_developer(&developer_id, &app_id)?.is_none() {
+            return Err(Error::AppNotFound);
+        }
+        s.update_app(app_id, |app| app.cycles_policy = policy)
+    })
+}
+
+/// Limit the spending of an app from the escrow account in each period, `None` removes the limit.
+#[ic_cdk::update]
+fn set_app_budget(
+    app_id: crate::app::AppID,
+    budget: Option<crate::app::budget::AppBudget>,
+) -> Result<()> {
+    let (developer_id, _) = Developer::get_caller_developer_account()?;
+    if let Some(budget) = &budget {
+        budget.validate()?;
+    }
+    STATE.with_borrow_mut(|s| {
+        if s.get_app_of_developer(&developer_id, &app_id)?.is_none() {
+            return Err(Error::AppNotFound);
+        }
+        s.set_app_budget(app_id, budget)
+    })
 }
 
 // Specific for canisters to request more cycles transferred to them.
 #[ic_cdk::update]
 async fn request_cycles(cycles: u64) -> Result<u128> {
     let app_id = ic_cdk::caller();
-    let escrow_account = STATE.with_borrow(|s| {
+    let (developer_id, developer) = STATE.with_borrow(|s| {
         let app = s.get_app(&app_id)?;
+        if !matches!(app.state, AppState::Active(_)) {
+            return Err(Error::AppNotFound);
+        }
         let developer = s.get_developer(&app.developer_id)?;
-        Ok(developer.escrow_account)
+        Ok::<_, Error>((app.developer_id, developer))
+    })?;
+    // Apps share the escrow of their developer, so they are guarded by the developer.
+    let _guard = OperationGuard::new(developer_id)?;
+    // Checked under the guard, so concurrent requests of the app can not exceed the limits.
+    STATE.with_borrow(|s| {
+        let app = s.get_app(&app_id)?;
+        app.cycles_requests
+            .check(&app.cycles_policy, cycles, ic_cdk::api::time())
+    })?;
+    let escrow_account = developer.escrow_account;
+
+    let quote = dto::CyclesQuote::new(cycles, icp_needed_for_cycles(cycles).await?);
+    STATE.with_borrow_mut(|s| {
+        s.check_app_budget(app_id, quote.icp + quote.commission, ic_cdk::api::time())
     })?;
+
+    if developer.standing_allowance.is_some() {
+        developer
+            .ensure_escrow_has_balance_from_standing_allowance(developer_id, quote.total)
+            .await?;
+    }
+
     let (cycles_topped_up, icp_tokens_used) =
-        top_up_canister(escrow_account, app_id, cycles).await?;
+        top_up_canister(developer_id, escrow_account, Some(app_id), cycles).await?;
+    let commission = charge_commission(developer_id, escrow_account, icp_tokens_used).await;
 
     let usage = AppUsage {
         kind: UsageKind::CyclesCharge {
@@ -170,22 +444,67 @@ async fn request_cycles(cycles: u64) -> Result<u128> {
             timestamp_nanos: ic_cdk::api::time(),
         },
         amount: icp_tokens_used,
+        commission,
         is_paid: true,
     };
 
+    let spent = icp_tokens_used + commission;
+    // The cycles are already transferred, failing to record them only happens when the app was
+    // removed, or can not be read, in the meantime.
     STATE
-        .with_borrow_mut(|s| s.register_usage(app_id, usage))
-        .unwrap();
+        .with_borrow_mut(|s| {
+            let now = ic_cdk::api::time();
+            s.update_app(app_id, |app| app.cycles_requests.record(cycles, now))?;
+            s.record_app_spending(app_id, spent, now);
+            s.register_usage(app_id, usage).map(|_| ())
+        })
+        .map_err(|e| {
+            Error::Internal(format!(
+                "Transferred {cycles_topped_up} cycles, but failed to record them, error: {e:?}"
+            ))
+        })?;
 
     Ok(cycles_topped_up)
 }
 
+/// Cost of `cycles` at the cached exchange rate, which is renewed in the background. A rate older
+/// than `MAX_RATE_AGE` is not used, as the background renewal has been failing for a while.
+fn quote(cycles: u64) -> Result<dto::CyclesQuote> {
+    let rate = STATE
+        .with_borrow(|s| s.icp_cycles_rate())
+        .filter(|rate| !rate.is_stale())
+        .ok_or(Error::ExchangeRateUnavailable)?;
+    Ok(dto::CyclesQuote::new(
+        cycles,
+        rate.icp_needed_for_cycles(cycles)?,
+    ))
+}
+
+#[ic_cdk::query]
+fn quote_cycles(cycles: u64) -> Result<crate::app::dto::CyclesQuote> {
+    quote(cycles)
+}
+
+#[ic_cdk::query]
+fn quote_deploy() -> Result<crate::app::dto::DeployQuote> {
+    Ok(dto::DeployQuote {
+        minimum_escrow_balance: STATE
+            .with_borrow(|s| s.settings().minimum_escrow_balance_for_deploy),
+        creation: quote(APP_CANISTER_CREATION_CYCLES)?,
+    })
+}
+
 pub mod dto {
     use super::*;
 
     #[derive(CandidType, Deserialize)]
     pub(super) enum AppState {
-        Active { revision: u32, name: String },
+        Active {
+            revision: u32,
+            name: String,
+            manifest: Manifest,
+            previous_revisions: Vec<AppRevision>,
+        },
         Deleted,
     }
 
@@ -194,18 +513,129 @@ pub mod dto {
         pub kind: UsageKind,
         pub timestamp: Timestamp,
         pub amount: Tokens,
+        pub commission: Tokens,
+        pub is_paid: bool,
+    }
+
+    impl From<super::AppUsage> for AppUsage {
+        fn from(usage: super::AppUsage) -> Self {
+            Self {
+                kind: usage.kind,
+                timestamp: usage.timestamp,
+                amount: usage.amount,
+                commission: usage.commission,
+                is_paid: usage.is_paid,
+            }
+        }
+    }
+
+    #[derive(CandidType, Deserialize)]
+    pub struct AppUsagesRequest {
+        pub app_id: AppID,
+        // Only the usages recorded from `from` and before `to`, when set.
+        pub from: Option<Timestamp>,
+        pub to: Option<Timestamp>,
+        // Where to continue from, the `next` cursor of the previous page.
+        pub cursor: Option<UsageCursor>,
+        pub limit: Option<u64>,
+    }
+
+    /// Position of a usage, among the usages of an app.
+    #[derive(CandidType, Deserialize)]
+    pub struct UsageCursor {
+        pub timestamp: Timestamp,
+        pub seq: u64,
+    }
+
+    #[derive(CandidType, Deserialize)]
+    pub struct AppUsagesPage {
+        pub(super) usages: Vec<AppUsage>,
+        // Not set on the last page.
+        pub(super) next: Option<UsageCursor>,
     }
 
     #[derive(CandidType, Deserialize)]
     pub struct AppDto {
         pub id: AppID,
         pub(super) state: AppState,
-        pub(super) usages: Vec<AppUsage>,
+        pub(super) cycles_policy: CyclesPolicy,
+        pub(super) budget: Option<AppBudget>,
+        // Spent in the current period of the budget.
+        pub(super) budget_spent: Option<Tokens>,
+    }
+
+    #[derive(CandidType, Deserialize)]
+    pub struct AppsPage {
+        pub(super) apps: Vec<AppDto>,
+        // The `cursor` of the next page, not set on the last page.
+        pub(super) next: Option<AppID>,
+    }
+
+    /// Tokens charged from the escrow account for converting them into cycles.
+    #[derive(CandidType, Deserialize)]
+    pub struct CyclesQuote {
+        pub cycles: u64,
+        pub icp: Tokens,
+        pub commission: Tokens,
+        pub ledger_fees: Tokens,
+        pub total: Tokens,
+    }
+
+    impl CyclesQuote {
+        pub fn new(cycles: u64, icp: Tokens) -> Self {
+            let commission = commission_of(icp);
+            // The top-up and the commission are separate transfers, paying a fee each.
+            let ledger_fees = if commission == Tokens::from_e8s(0) {
+                DEFAULT_FEE
+            } else {
+                DEFAULT_FEE + DEFAULT_FEE
+            };
+            Self {
+                cycles,
+                icp,
+                commission,
+                ledger_fees,
+                total: icp + commission + ledger_fees,
+            }
+        }
+    }
+
+    #[derive(CandidType, Deserialize)]
+    pub struct DeployQuote {
+        // Balance the escrow account should have to deploy an app.
+        pub minimum_escrow_balance: Tokens,
+        // Cost of creating the app canister, charged on deploy.
+        pub creation: CyclesQuote,
     }
 
     #[derive(CandidType, Deserialize)]
     pub struct DeployAppRequest {
         pub name: String,
-        pub app_data: Vec<u8>,
+        pub app_data: AppData,
+    }
+
+    #[derive(CandidType, Deserialize)]
+    pub enum AppData {
+        Inline(Vec<u8>),
+        /// A finalized chunked upload, see `begin_upload`.
+        Upload(UploadID),
+    }
+
+    impl AppData {
+        pub fn load(&self, developer_id: &DeveloperID) -> Result<Vec<u8>> {
+            match self {
+                AppData::Inline(data) => Ok(data.clone()),
+                AppData::Upload(upload_id) => {
+                    STATE.with_borrow(|s| s.get_upload_data(developer_id, *upload_id))
+                }
+            }
+        }
+
+        /// Drop the uploaded data once it is deployed.
+        pub fn consume(&self) {
+            if let AppData::Upload(upload_id) = self {
+                STATE.with_borrow_mut(|s| s.remove_upload(*upload_id));
+            }
+        }
     }
 }
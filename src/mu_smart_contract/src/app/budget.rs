@@ -0,0 +1,174 @@
+use candid::CandidType;
+use candid::Deserialize;
+use ic_ledger_types::Tokens;
+
+use crate::error::Error;
+use crate::Result;
+
+/// Maximum amount an app can spend from the escrow account of its developer in each period.
+/// Charges count with their commission, the ledger fees are not counted.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AppBudget {
+    pub amount: Tokens,
+    pub period_seconds: u64,
+}
+
+impl AppBudget {
+    pub fn validate(&self) -> Result<()> {
+        if self.period_seconds == 0 {
+            return Err(Error::InvalidBudget(
+                "`period_seconds` should be greater than zero".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn period_nanos(&self) -> u64 {
+        self.period_seconds.saturating_mul(1_000_000_000)
+    }
+}
+
+/// Spending of an app in the current period of its budget.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct BudgetSpending {
+    pub(crate) period_started_at: u64,
+    pub(crate) spent: Tokens,
+    // The developer is notified once per period when the budget is exceeded.
+    pub(crate) notified: bool,
+}
+
+impl Default for BudgetSpending {
+    fn default() -> Self {
+        Self {
+            period_started_at: 0,
+            spent: Tokens::from_e8s(0),
+            notified: false,
+        }
+    }
+}
+
+impl BudgetSpending {
+    pub fn spent(&self, budget: &AppBudget, now: u64) -> Tokens {
+        if self.is_period_over(budget, now) {
+            Tokens::from_e8s(0)
+        } else {
+            self.spent
+        }
+    }
+
+    pub fn check(&self, budget: &AppBudget, amount: Tokens, now: u64) -> Result<()> {
+        let spent = self.spent(budget, now);
+        if spent.e8s().saturating_add(amount.e8s()) > budget.amount.e8s() {
+            return Err(Error::AppBudgetExceeded {
+                remaining: Tokens::from_e8s(budget.amount.e8s().saturating_sub(spent.e8s())),
+            });
+        }
+        Ok(())
+    }
+
+    pub fn record(&mut self, budget: &AppBudget, amount: Tokens, now: u64) {
+        self.start_period_if_over(budget, now);
+        self.spent = Tokens::from_e8s(self.spent.e8s().saturating_add(amount.e8s()));
+    }
+
+    /// Whether the developer should be notified of the budget being exceeded, only the first time
+    /// in a period.
+    pub fn should_notify(&mut self, budget: &AppBudget, now: u64) -> bool {
+        self.start_period_if_over(budget, now);
+        !std::mem::replace(&mut self.notified, true)
+    }
+
+    fn start_period_if_over(&mut self, budget: &AppBudget, now: u64) {
+        if self.is_period_over(budget, now) {
+            *self = Self {
+                period_started_at: now,
+                ..Self::default()
+            };
+        }
+    }
+
+    // Periods start with the first spending after the previous period is over.
+    fn is_period_over(&self, budget: &AppBudget, now: u64) -> bool {
+        now.saturating_sub(self.period_started_at) >= budget.period_nanos()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND_NANOS: u64 = 1_000_000_000;
+
+    fn budget(e8s: u64, period_seconds: u64) -> AppBudget {
+        AppBudget {
+            amount: Tokens::from_e8s(e8s),
+            period_seconds,
+        }
+    }
+
+    #[test]
+    fn allows_spending_exactly_the_budget() {
+        let budget = budget(100, 60);
+        let mut spending = BudgetSpending::default();
+        spending.record(&budget, Tokens::from_e8s(60), 0);
+        assert!(spending.check(&budget, Tokens::from_e8s(40), 1).is_ok());
+        assert!(matches!(
+            spending.check(&budget, Tokens::from_e8s(41), 1),
+            Err(Error::AppBudgetExceeded { remaining }) if remaining == Tokens::from_e8s(40)
+        ));
+    }
+
+    #[test]
+    fn starts_a_new_period_once_the_previous_one_is_over() {
+        let budget = budget(100, 60);
+        let mut spending = BudgetSpending::default();
+        spending.record(&budget, Tokens::from_e8s(100), 0);
+        assert!(spending
+            .check(&budget, Tokens::from_e8s(1), 60 * SECOND_NANOS - 1)
+            .is_err());
+        assert_eq!(
+            Tokens::from_e8s(0),
+            spending.spent(&budget, 60 * SECOND_NANOS)
+        );
+        assert!(spending
+            .check(&budget, Tokens::from_e8s(100), 60 * SECOND_NANOS)
+            .is_ok());
+
+        // The new period starts with its first spending.
+        spending.record(&budget, Tokens::from_e8s(30), 70 * SECOND_NANOS);
+        assert_eq!(
+            Tokens::from_e8s(30),
+            spending.spent(&budget, 129 * SECOND_NANOS)
+        );
+        assert_eq!(
+            Tokens::from_e8s(0),
+            spending.spent(&budget, 130 * SECOND_NANOS)
+        );
+    }
+
+    #[test]
+    fn zero_budget_refuses_any_spending() {
+        let budget = budget(0, 60);
+        let spending = BudgetSpending::default();
+        assert!(spending.check(&budget, Tokens::from_e8s(0), 0).is_ok());
+        assert!(matches!(
+            spending.check(&budget, Tokens::from_e8s(1), 0),
+            Err(Error::AppBudgetExceeded { remaining }) if remaining == Tokens::from_e8s(0)
+        ));
+    }
+
+    #[test]
+    fn zero_period_is_not_valid() {
+        assert!(budget(100, 0).validate().is_err());
+        assert!(budget(100, 1).validate().is_ok());
+    }
+
+    #[test]
+    fn notifies_once_per_period() {
+        let budget = budget(100, 60);
+        let mut spending = BudgetSpending::default();
+        assert!(spending.should_notify(&budget, 0));
+        assert!(!spending.should_notify(&budget, 59 * SECOND_NANOS));
+        assert!(spending.should_notify(&budget, 60 * SECOND_NANOS));
+    }
+}
@@ -0,0 +1,383 @@
+use candid::Encode;
+use candid::Principal;
+use walrus::ir::BinaryOp;
+use walrus::ir::LoadKind;
+use walrus::ir::MemArg;
+use walrus::ir::StoreKind;
+use walrus::ir::Value;
+use walrus::ElementKind;
+use walrus::ExportItem;
+use walrus::FunctionBuilder;
+use walrus::FunctionId;
+use walrus::InitExpr;
+use walrus::Module;
+use walrus::ValType;
+
+use crate::error::Error;
+use crate::Result;
+
+const REQUEST_CYCLES_METHOD: &str = "request_cycles";
+
+// Minimum time between two checks of the app balance. Heartbeats in between only read the time,
+// and a failing or slow request is not repeated on every heartbeat.
+const CHECK_INTERVAL_NANOS: i64 = 10 * 60 * 1_000_000_000;
+
+// Functions of the IC System API called by the controller, with their parameters and results.
+pub const CONTROLLER_IC0_IMPORTS: &[(&str, &[ValType], &[ValType])] = &[
+    ("canister_cycle_balance128", &[ValType::I32], &[]),
+    ("time", &[], &[ValType::I64]),
+    ("call_new", &[ValType::I32; 8], &[]),
+    ("call_data_append", &[ValType::I32; 2], &[]),
+    ("call_perform", &[], &[ValType::I32]),
+];
+
+// Size of the memory region at address 0 used for the balance and the call payload, it is saved
+// before and restored after the check so the app memory is left untouched.
+const SCRATCH_WORDS: u32 = 8;
+
+/// Controller code injected into app canisters, requesting `amount` cycles from `controller`
+/// whenever the app balance drops below `threshold`.
+pub struct CyclesController {
+    pub controller: Principal,
+    pub threshold: u64,
+    pub amount: u64,
+}
+
+impl CyclesController {
+    /// Rewrite `wasm_module` so that its `canister_heartbeat` runs the controller, at most once
+    /// every `CHECK_INTERVAL_NANOS`, before the original heartbeat of the app, if any.
+    pub fn inject(&self, wasm_module: &[u8]) -> Result<Vec<u8>> {
+        let mut module = Module::from_buffer(wasm_module)
+            .map_err(|e| Error::InvalidWasmModule(format!("{e:#}")))?;
+
+        let payload = self.call_payload();
+        let callee_size = self.controller.as_slice().len() as i32;
+        let method_size = REQUEST_CYCLES_METHOD.len() as i32;
+        let arg_size = payload.len() as i32 - callee_size - method_size;
+
+        let existing_memory = module.memories.iter().next().map(|m| m.id());
+        let memory = match existing_memory {
+            Some(memory) => memory,
+            None => module.memories.add_local(false, 1, None),
+        };
+
+        let cycle_balance = ic0_function(&mut module, "canister_cycle_balance128");
+        let time = ic0_function(&mut module, "time");
+        let call_new = ic0_function(&mut module, "call_new");
+        let call_data_append = ic0_function(&mut module, "call_data_append");
+        let call_perform = ic0_function(&mut module, "call_perform");
+
+        let callback_index = add_callback(&mut module)?;
+        let last_check =
+            module
+                .globals
+                .add_local(ValType::I64, true, InitExpr::Value(Value::I64(0)));
+        let saved_words: Vec<_> = (0..SCRATCH_WORDS)
+            .map(|_| module.locals.add(ValType::I64))
+            .collect();
+        let existing_heartbeat = module.exports.get_func("canister_heartbeat").ok();
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder
+            .func_body()
+            .call(time)
+            .global_get(last_check)
+            .binop(BinaryOp::I64Sub)
+            .i64_const(CHECK_INTERVAL_NANOS)
+            .binop(BinaryOp::I64GeU)
+            .memory_size(memory)
+            .i32_const(0)
+            .binop(BinaryOp::I32Ne)
+            .binop(BinaryOp::I32And)
+            .if_else(
+                None,
+                |check| {
+                    check.call(time).global_set(last_check);
+
+                    for (i, local) in saved_words.iter().enumerate() {
+                        check
+                            .i32_const(0)
+                            .load(memory, LoadKind::I64 { atomic: false }, word_arg(i))
+                            .local_set(*local);
+                    }
+
+                    // The balance is a 128-bit number, below the threshold when its high word is
+                    // zero and its low word is below the threshold.
+                    check
+                        .i32_const(0)
+                        .call(cycle_balance)
+                        .i32_const(0)
+                        .load(memory, LoadKind::I64 { atomic: false }, word_arg(1))
+                        .i64_const(0)
+                        .binop(BinaryOp::I64Eq)
+                        .i32_const(0)
+                        .load(memory, LoadKind::I64 { atomic: false }, word_arg(0))
+                        .i64_const(self.threshold as i64)
+                        .binop(BinaryOp::I64LtU)
+                        .binop(BinaryOp::I32And)
+                        .if_else(
+                            None,
+                            |request| {
+                                for (i, word) in payload.chunks(8).enumerate() {
+                                    let mut bytes = [0; 8];
+                                    bytes[..word.len()].copy_from_slice(word);
+                                    request
+                                        .i32_const(0)
+                                        .i64_const(i64::from_le_bytes(bytes))
+                                        .store(
+                                            memory,
+                                            StoreKind::I64 { atomic: false },
+                                            word_arg(i),
+                                        );
+                                }
+
+                                request
+                                    .i32_const(0)
+                                    .i32_const(callee_size)
+                                    .i32_const(callee_size)
+                                    .i32_const(method_size)
+                                    .i32_const(callback_index)
+                                    .i32_const(0)
+                                    .i32_const(callback_index)
+                                    .i32_const(0)
+                                    .call(call_new)
+                                    .i32_const(callee_size + method_size)
+                                    .i32_const(arg_size)
+                                    .call(call_data_append)
+                                    .call(call_perform)
+                                    .drop();
+                            },
+                            |_| {},
+                        );
+
+                    for (i, local) in saved_words.iter().enumerate() {
+                        check.i32_const(0).local_get(*local).store(
+                            memory,
+                            StoreKind::I64 { atomic: false },
+                            word_arg(i),
+                        );
+                    }
+                },
+                |_| {},
+            );
+        if let Some(existing_heartbeat) = existing_heartbeat {
+            builder.func_body().call(existing_heartbeat);
+        }
+        let heartbeat = builder.finish(vec![], &mut module.funcs);
+
+        let existing_export = module
+            .exports
+            .iter()
+            .find(|e| e.name == "canister_heartbeat")
+            .map(|e| e.id());
+        match existing_export {
+            Some(export) => module.exports.get_mut(export).item = ExportItem::Function(heartbeat),
+            None => {
+                module.exports.add("canister_heartbeat", heartbeat);
+            }
+        }
+
+        Ok(module.emit_wasm())
+    }
+
+    /// Callee principal, method name and candid encoded argument of the `request_cycles` call.
+    fn call_payload(&self) -> Vec<u8> {
+        let mut payload = self.controller.as_slice().to_vec();
+        payload.extend_from_slice(REQUEST_CYCLES_METHOD.as_bytes());
+        payload.extend_from_slice(&Encode!(&self.amount).unwrap());
+        assert!(payload.len() <= (SCRATCH_WORDS * 8) as usize);
+        payload
+    }
+}
+
+fn word_arg(index: usize) -> MemArg {
+    MemArg {
+        align: 8,
+        offset: index as u32 * 8,
+    }
+}
+
+/// Get the function imported from the IC System API, importing it if the app does not already.
+/// Existing imports are checked to have the signature of `CONTROLLER_IC0_IMPORTS` on upload.
+fn ic0_function(module: &mut Module, name: &str) -> FunctionId {
+    if let Ok(function) = module.imports.get_func("ic0", name) {
+        return function;
+    }
+    let (_, params, results) = CONTROLLER_IC0_IMPORTS
+        .iter()
+        .find(|(import, _, _)| *import == name)
+        .expect("The controller should only call the functions it declares");
+    let ty = module.types.add(params, results);
+    module.add_import_func("ic0", name, ty).0
+}
+
+/// Add a no-op reply and reject callback to the function table, returning its table index.
+fn add_callback(module: &mut Module) -> Result<i32> {
+    let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[]);
+    builder.func_body();
+    let env = module.locals.add(ValType::I32);
+    let callback = builder.finish(vec![env], &mut module.funcs);
+
+    let existing_table = module.tables.iter().next().map(|t| (t.id(), t.element_ty));
+    let table_id = match existing_table {
+        Some((_, element_ty)) if element_ty != ValType::Funcref => {
+            return Err(Error::InvalidWasmModule(
+                "The function table should have `funcref` elements".into(),
+            ))
+        }
+        Some((table_id, _)) => table_id,
+        None => module.tables.add_local(0, None, ValType::Funcref),
+    };
+
+    let table = module.tables.get_mut(table_id);
+    let index = table.initial;
+    table.initial += 1;
+    if let Some(maximum) = table.maximum.as_mut() {
+        *maximum = (*maximum).max(table.initial);
+    }
+
+    let element = module.elements.add(
+        ElementKind::Active {
+            table: table_id,
+            offset: InitExpr::Value(Value::I32(index as i32)),
+        },
+        ValType::Funcref,
+        vec![Some(callback)],
+    );
+    module
+        .tables
+        .get_mut(table_id)
+        .elem_segments
+        .insert(element);
+
+    Ok(index as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use walrus::ir::Call;
+    use walrus::ir::Instr;
+    use walrus::FunctionKind;
+    use walrus::ModuleConfig;
+
+    use super::*;
+    use crate::app::wasm::validate_wasm_module;
+
+    fn controller() -> CyclesController {
+        CyclesController {
+            controller: Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 1, 1, 1]),
+            threshold: 1_000_000_000_000,
+            amount: 2_000_000_000_000,
+        }
+    }
+
+    /// A module exporting an empty function under each of `exports`, without memory or table.
+    fn test_module(exports: &[&str]) -> Vec<u8> {
+        let mut module = Module::with_config(ModuleConfig::new());
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body();
+        let function = builder.finish(vec![], &mut module.funcs);
+        for name in exports {
+            module.exports.add(name, function);
+        }
+        module.emit_wasm()
+    }
+
+    fn heartbeat_exports(module: &Module) -> usize {
+        module
+            .exports
+            .iter()
+            .filter(|e| e.name == "canister_heartbeat")
+            .count()
+    }
+
+    #[test]
+    fn injects_a_heartbeat_into_a_minimal_module() {
+        let injected = controller()
+            .inject(&test_module(&["canister_query get"]))
+            .unwrap();
+        assert!(validate_wasm_module(&injected, u64::MAX).is_ok());
+
+        let module = Module::from_buffer(&injected).unwrap();
+        assert_eq!(1, heartbeat_exports(&module));
+        assert!(module.exports.get_func("canister_query get").is_ok());
+        assert_eq!(1, module.memories.iter().count());
+        assert_eq!(1, module.tables.iter().count());
+        for name in [
+            "canister_cycle_balance128",
+            "time",
+            "call_new",
+            "call_data_append",
+            "call_perform",
+        ] {
+            assert!(module.imports.get_func("ic0", name).is_ok(), "ic0.{name}");
+        }
+    }
+
+    #[test]
+    fn keeps_the_heartbeat_of_the_app() {
+        // The app heartbeat is the same function as its `get` method.
+        let injected = controller()
+            .inject(&test_module(&["canister_query get", "canister_heartbeat"]))
+            .unwrap();
+        assert!(validate_wasm_module(&injected, u64::MAX).is_ok());
+
+        let module = Module::from_buffer(&injected).unwrap();
+        assert_eq!(1, heartbeat_exports(&module));
+        let app_heartbeat = module.exports.get_func("canister_query get").unwrap();
+        let heartbeat = module.exports.get_func("canister_heartbeat").unwrap();
+        assert_ne!(app_heartbeat, heartbeat);
+
+        // Called at the end of the injected heartbeat.
+        let FunctionKind::Local(heartbeat) = &module.funcs.get(heartbeat).kind else {
+            panic!("The heartbeat should be a local function");
+        };
+        let instructions = &heartbeat.block(heartbeat.entry_block()).instrs;
+        assert!(matches!(
+            instructions.last(),
+            Some((Instr::Call(Call { func }), _)) if *func == app_heartbeat
+        ));
+    }
+
+    #[test]
+    fn reads_the_time_before_anything_else_on_heartbeats() {
+        let injected = controller()
+            .inject(&test_module(&["canister_query get"]))
+            .unwrap();
+        let module = Module::from_buffer(&injected).unwrap();
+        let time = module.imports.get_func("ic0", "time").unwrap();
+        assert!(module
+            .imports
+            .get_func("ic0", "canister_cycle_balance")
+            .is_err());
+
+        // The balance is only checked once the interval since the last check is over.
+        let heartbeat = module.exports.get_func("canister_heartbeat").unwrap();
+        let FunctionKind::Local(heartbeat) = &module.funcs.get(heartbeat).kind else {
+            panic!("The heartbeat should be a local function");
+        };
+        let instructions = &heartbeat.block(heartbeat.entry_block()).instrs;
+        assert!(matches!(
+            instructions.first(),
+            Some((Instr::Call(Call { func }), _)) if *func == time
+        ));
+        assert!(instructions
+            .iter()
+            .all(|(instr, _)| !matches!(instr, Instr::Call(Call { func }) if *func != time)));
+    }
+
+    #[test]
+    fn fits_the_call_payload_in_the_scratch_memory() {
+        let payload = controller().call_payload();
+        assert!(payload.len() <= (SCRATCH_WORDS * 8) as usize);
+        assert!(payload.ends_with(&Encode!(&2_000_000_000_000_u64).unwrap()));
+    }
+
+    #[test]
+    fn rejects_a_module_that_is_not_wasm() {
+        assert!(matches!(
+            controller().inject(b"not a wasm module"),
+            Err(Error::InvalidWasmModule(_))
+        ));
+    }
+}
@@ -0,0 +1,191 @@
+use candid::CandidType;
+use candid::Deserialize;
+use ic_ledger_types::Timestamp;
+
+use crate::error::Error;
+use crate::settings::Settings;
+use crate::Result;
+
+const DAY_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// Limits on the cycles an app can request from the escrow account of its developer, the ones
+/// left empty are not limited.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CyclesPolicy {
+    pub max_cycles_per_request: Option<u64>,
+    pub max_cycles_per_day: Option<u64>,
+    pub cooldown_seconds: Option<u64>,
+}
+
+impl CyclesPolicy {
+    /// Policy of new apps, allowing the requests of the injected cycles controller.
+    pub fn new(settings: &Settings) -> Self {
+        Self {
+            max_cycles_per_request: Some(settings.cycles_request_amount),
+            max_cycles_per_day: Some(settings.max_cycles_per_day),
+            cooldown_seconds: Some(settings.cycles_request_cooldown_seconds),
+        }
+    }
+}
+
+/// Cycles requests of an app, for the limits of its `CyclesPolicy`.
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct CyclesRequests {
+    pub(crate) day_started_at: u64,
+    pub(crate) requested_in_day: u64,
+    pub(crate) last_requested_at: Option<u64>,
+}
+
+impl CyclesRequests {
+    pub fn check(&self, policy: &CyclesPolicy, cycles: u64, now: u64) -> Result<()> {
+        if let Some(max) = policy.max_cycles_per_request {
+            if cycles > max {
+                return Err(Error::CyclesRequestAboveLimit { max });
+            }
+        }
+
+        if let (Some(cooldown_seconds), Some(last_requested_at)) =
+            (policy.cooldown_seconds, self.last_requested_at)
+        {
+            let next_request_at =
+                last_requested_at.saturating_add(cooldown_seconds.saturating_mul(1_000_000_000));
+            if now < next_request_at {
+                return Err(Error::CyclesRequestTooSoon {
+                    next_request_at: Timestamp {
+                        timestamp_nanos: next_request_at,
+                    },
+                });
+            }
+        }
+
+        if let Some(max) = policy.max_cycles_per_day {
+            let requested = self.requested_in_day_of(now);
+            if requested.saturating_add(cycles) > max {
+                return Err(Error::DailyCyclesLimitReached {
+                    remaining: max.saturating_sub(requested),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn record(&mut self, cycles: u64, now: u64) {
+        if self.is_day_over(now) {
+            self.day_started_at = now;
+            self.requested_in_day = 0;
+        }
+        self.requested_in_day = self.requested_in_day.saturating_add(cycles);
+        self.last_requested_at = Some(now);
+    }
+
+    fn requested_in_day_of(&self, now: u64) -> u64 {
+        if self.is_day_over(now) {
+            0
+        } else {
+            self.requested_in_day
+        }
+    }
+
+    // Days start with the first request after the previous day is over.
+    fn is_day_over(&self, now: u64) -> bool {
+        now.saturating_sub(self.day_started_at) >= DAY_NANOS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND_NANOS: u64 = 1_000_000_000;
+
+    fn policy(
+        max_cycles_per_request: Option<u64>,
+        max_cycles_per_day: Option<u64>,
+        cooldown_seconds: Option<u64>,
+    ) -> CyclesPolicy {
+        CyclesPolicy {
+            max_cycles_per_request,
+            max_cycles_per_day,
+            cooldown_seconds,
+        }
+    }
+
+    #[test]
+    fn allows_requests_of_exactly_the_limit() {
+        let policy = policy(Some(100), None, None);
+        let requests = CyclesRequests::default();
+        assert!(requests.check(&policy, 100, 0).is_ok());
+        assert!(matches!(
+            requests.check(&policy, 101, 0),
+            Err(Error::CyclesRequestAboveLimit { max: 100 })
+        ));
+    }
+
+    #[test]
+    fn allows_requests_up_to_exactly_the_daily_limit() {
+        let policy = policy(None, Some(100), None);
+        let mut requests = CyclesRequests::default();
+        requests.record(60, 0);
+        assert!(requests.check(&policy, 40, 1).is_ok());
+        assert!(matches!(
+            requests.check(&policy, 41, 1),
+            Err(Error::DailyCyclesLimitReached { remaining: 40 })
+        ));
+    }
+
+    #[test]
+    fn starts_a_new_day_once_the_previous_one_is_over() {
+        let policy = policy(None, Some(100), None);
+        let mut requests = CyclesRequests::default();
+        requests.record(100, 0);
+        assert!(requests.check(&policy, 1, DAY_NANOS - 1).is_err());
+        assert!(requests.check(&policy, 100, DAY_NANOS).is_ok());
+
+        // The new day starts with its first request, not at the end of the previous one.
+        requests.record(100, DAY_NANOS + 10);
+        assert!(requests.check(&policy, 1, 2 * DAY_NANOS).is_err());
+        assert!(requests.check(&policy, 1, 2 * DAY_NANOS + 10).is_ok());
+    }
+
+    #[test]
+    fn waits_exactly_the_cooldown() {
+        let policy = policy(None, None, Some(10));
+        let mut requests = CyclesRequests::default();
+        assert!(requests.check(&policy, 1, 0).is_ok());
+        requests.record(1, 5 * SECOND_NANOS);
+
+        let next_request_at = 15 * SECOND_NANOS;
+        assert!(matches!(
+            requests.check(&policy, 1, next_request_at - 1),
+            Err(Error::CyclesRequestTooSoon { next_request_at: Timestamp { timestamp_nanos } })
+                if timestamp_nanos == next_request_at
+        ));
+        assert!(requests.check(&policy, 1, next_request_at).is_ok());
+    }
+
+    #[test]
+    fn zero_limits_refuse_every_request() {
+        let policy = policy(Some(0), Some(0), None);
+        let requests = CyclesRequests::default();
+        assert!(requests.check(&policy, 0, 0).is_ok());
+        assert!(matches!(
+            requests.check(&policy, 1, 0),
+            Err(Error::CyclesRequestAboveLimit { max: 0 })
+        ));
+
+        let policy = self::policy(None, Some(0), None);
+        assert!(matches!(
+            requests.check(&policy, 1, 0),
+            Err(Error::DailyCyclesLimitReached { remaining: 0 })
+        ));
+    }
+
+    #[test]
+    fn empty_policy_does_not_limit_requests() {
+        let policy = policy(None, None, None);
+        let mut requests = CyclesRequests::default();
+        requests.record(u64::MAX, 0);
+        assert!(requests.check(&policy, u64::MAX, 0).is_ok());
+    }
+}
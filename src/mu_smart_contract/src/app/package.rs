@@ -0,0 +1,141 @@
+use candid::CandidType;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::app::cycles_controller::CyclesController;
+use crate::app::wasm::validate_wasm_module;
+use crate::error::Error;
+use crate::memory::STATE;
+use crate::Result;
+
+/// App package as produced by the mu CLI, a CBOR encoded map of the mu-manifest and the app
+/// wasm module.
+#[derive(Serialize, Deserialize)]
+pub struct AppPackage {
+    pub manifest: Manifest,
+    #[serde(with = "serde_bytes")]
+    pub wasm_module: Vec<u8>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Manifest {
+    pub name: String,
+    pub version: String,
+    #[serde(default)]
+    pub description: Option<String>,
+}
+
+impl AppPackage {
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let package: AppPackage = ciborium::from_reader(bytes)
+            .map_err(|e| Error::InvalidAppPackage(format!("Failed to decode package: {e}")))?;
+        package.validate()?;
+        Ok(package)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.manifest.name.trim().is_empty() {
+            return Err(Error::InvalidAppPackage(
+                "Manifest `name` can not be empty".into(),
+            ));
+        }
+        if self.manifest.version.trim().is_empty() {
+            return Err(Error::InvalidAppPackage(
+                "Manifest `version` can not be empty".into(),
+            ));
+        }
+        if self.wasm_module.is_empty() {
+            return Err(Error::InvalidAppPackage("Wasm module is empty".into()));
+        }
+
+        let max_wasm_module_size = STATE.with_borrow(|s| s.settings().max_wasm_module_size);
+        validate_wasm_module(&self.wasm_module, max_wasm_module_size)
+    }
+
+    /// The wasm module to install on the app canister, with the cycles controller injected.
+    pub fn build_wasm_module(&self) -> Result<Vec<u8>> {
+        let controller = STATE.with_borrow(|s| CyclesController {
+            controller: ic_cdk::id(),
+            threshold: s.settings().cycles_request_threshold,
+            amount: s.settings().cycles_request_amount,
+        });
+        controller.inject(&self.wasm_module)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(package: &impl Serialize) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(package, &mut bytes).unwrap();
+        bytes
+    }
+
+    fn package(name: &str, version: &str, wasm_module: &[u8]) -> AppPackage {
+        AppPackage {
+            manifest: Manifest {
+                name: name.into(),
+                version: version.into(),
+                description: None,
+            },
+            wasm_module: wasm_module.to_vec(),
+        }
+    }
+
+    fn assert_invalid(result: Result<AppPackage>, expected: &str) {
+        match result {
+            Err(Error::InvalidAppPackage(reason)) => assert!(
+                reason.contains(expected),
+                "`{reason}` should contain `{expected}`"
+            ),
+            Err(e) => panic!("Invalid result, should fail with `InvalidAppPackage`: {e:?}"),
+            Ok(_) => panic!("Invalid result, should fail with `InvalidAppPackage`"),
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_cbor() {
+        assert_invalid(AppPackage::decode(&[]), "Failed to decode package");
+        assert_invalid(
+            AppPackage::decode(&[0xff, 0x00, 0x13]),
+            "Failed to decode package",
+        );
+
+        let bytes = encode(&package("TestApp", "0.1.0", b"\0asm"));
+        assert_invalid(
+            AppPackage::decode(&bytes[..bytes.len() - 1]),
+            "Failed to decode package",
+        );
+    }
+
+    #[test]
+    fn rejects_a_package_without_wasm_module() {
+        #[derive(Serialize)]
+        struct ManifestOnly {
+            manifest: Manifest,
+        }
+
+        let bytes = encode(&ManifestOnly {
+            manifest: package("TestApp", "0.1.0", &[]).manifest,
+        });
+        assert_invalid(AppPackage::decode(&bytes), "Failed to decode package");
+        assert_invalid(
+            AppPackage::decode(&encode(&package("TestApp", "0.1.0", &[]))),
+            "Wasm module is empty",
+        );
+    }
+
+    #[test]
+    fn rejects_a_manifest_without_name_or_version() {
+        assert_invalid(
+            AppPackage::decode(&encode(&package(" ", "0.1.0", b"\0asm"))),
+            "Manifest `name` can not be empty",
+        );
+        assert_invalid(
+            AppPackage::decode(&encode(&package("TestApp", "", b"\0asm"))),
+            "Manifest `version` can not be empty",
+        );
+    }
+}
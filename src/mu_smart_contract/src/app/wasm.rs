@@ -0,0 +1,305 @@
+use walrus::ExportItem;
+use walrus::ImportKind;
+use walrus::Module;
+
+use crate::app::cycles_controller::CONTROLLER_IC0_IMPORTS;
+use crate::error::Error;
+use crate::Result;
+
+const WASM_MAGIC: &[u8] = b"\0asm";
+const WASM_VERSION: &[u8] = &[0x01, 0x00, 0x00, 0x00];
+
+// Limits enforced by the replica when installing a module.
+const MAX_EXPORTED_METHODS: usize = 1000;
+const MAX_SUM_EXPORTED_METHOD_NAME_LENGTHS: usize = 20_000;
+
+// Functions of the IC System API that only the cycles minting canister can use. Any other
+// function can be imported, so apps built with newer CDKs are accepted.
+const DENIED_IC0_IMPORTS: &[&str] = &["mint_cycles", "mint_cycles128"];
+
+// Exports the system calls into, other than the canister methods.
+const SYSTEM_EXPORTS: &[&str] = &[
+    "canister_init",
+    "canister_pre_upgrade",
+    "canister_post_upgrade",
+    "canister_inspect_message",
+    "canister_heartbeat",
+    "canister_global_timer",
+    "canister_on_low_wasm_memory",
+];
+
+const METHOD_EXPORT_PREFIXES: &[&str] = &[
+    "canister_query ",
+    "canister_update ",
+    "canister_composite_query ",
+];
+
+fn invalid(reason: impl Into<String>) -> Error {
+    Error::InvalidWasmModule(reason.into())
+}
+
+/// Check that `wasm_module` can be installed and run as an app canister.
+pub fn validate_wasm_module(wasm_module: &[u8], max_size: u64) -> Result<()> {
+    let size = wasm_module.len() as u64;
+    if size > max_size {
+        return Err(Error::WasmModuleTooLarge {
+            size,
+            max: max_size,
+        });
+    }
+
+    if !wasm_module.starts_with(WASM_MAGIC) {
+        return Err(invalid("Missing wasm magic header"));
+    }
+    if wasm_module.get(4..8) != Some(WASM_VERSION) {
+        return Err(invalid("Unsupported wasm version, expected version 1"));
+    }
+
+    let module = Module::from_buffer(wasm_module).map_err(|e| invalid(format!("{e:#}")))?;
+    validate_memories(&module)?;
+    validate_imports(&module)?;
+    validate_exports(&module)
+}
+
+fn validate_memories(module: &Module) -> Result<()> {
+    if module.memories.iter().count() > 1 {
+        return Err(invalid("Multiple memories are not supported"));
+    }
+    if module.memories.iter().any(|m| m.shared) {
+        return Err(invalid("Shared memories are not supported"));
+    }
+    Ok(())
+}
+
+fn validate_imports(module: &Module) -> Result<()> {
+    for import in module.imports.iter() {
+        if import.module != "ic0" {
+            return Err(invalid(format!(
+                "Import `{}.{}` is not allowed, only `ic0` imports are supported",
+                import.module, import.name
+            )));
+        }
+        if !matches!(import.kind, ImportKind::Function(_)) {
+            return Err(invalid(format!(
+                "Import `ic0.{}` is not a function",
+                import.name
+            )));
+        }
+        if DENIED_IC0_IMPORTS.contains(&import.name.as_str()) {
+            return Err(invalid(format!(
+                "Import `ic0.{}` is not allowed",
+                import.name
+            )));
+        }
+    }
+    validate_controller_imports(module)
+}
+
+// The cycles controller calls the functions the app already imports, they should have the
+// signatures it expects.
+fn validate_controller_imports(module: &Module) -> Result<()> {
+    for (name, params, results) in CONTROLLER_IC0_IMPORTS {
+        let Ok(function_id) = module.imports.get_func("ic0", name) else {
+            continue;
+        };
+        let ty = module.types.get(module.funcs.get(function_id).ty());
+        if ty.params() != *params || ty.results() != *results {
+            return Err(invalid(format!(
+                "Import `ic0.{name}` does not have the signature of the IC System API"
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn validate_exports(module: &Module) -> Result<()> {
+    let mut methods_count = 0;
+    let mut methods_name_lengths = 0;
+
+    for export in module.exports.iter() {
+        let name = export.name.as_str();
+        let method_name = METHOD_EXPORT_PREFIXES
+            .iter()
+            .find_map(|prefix| name.strip_prefix(prefix));
+
+        if method_name.is_none() && !SYSTEM_EXPORTS.contains(&name) {
+            if name.starts_with("canister_") {
+                return Err(invalid(format!(
+                    "Export `{name}` is not a valid system export"
+                )));
+            }
+            continue;
+        }
+
+        let ExportItem::Function(function_id) = export.item else {
+            return Err(invalid(format!("Export `{name}` is not a function")));
+        };
+        let ty = module.types.get(module.funcs.get(function_id).ty());
+        if !ty.params().is_empty() || !ty.results().is_empty() {
+            return Err(invalid(format!(
+                "Export `{name}` should not have any parameters or results"
+            )));
+        }
+
+        if let Some(method_name) = method_name {
+            methods_count += 1;
+            methods_name_lengths += method_name.len();
+        }
+    }
+
+    if methods_count == 0 {
+        return Err(invalid("Module does not export any canister methods"));
+    }
+    if methods_count > MAX_EXPORTED_METHODS {
+        return Err(invalid(format!(
+            "Module exports {methods_count} canister methods, the limit is {MAX_EXPORTED_METHODS}"
+        )));
+    }
+    if methods_name_lengths > MAX_SUM_EXPORTED_METHOD_NAME_LENGTHS {
+        return Err(invalid(format!(
+            "Sum of exported method name lengths is {methods_name_lengths}, the limit is {MAX_SUM_EXPORTED_METHOD_NAME_LENGTHS}"
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use walrus::FunctionBuilder;
+    use walrus::ModuleConfig;
+
+    use super::*;
+
+    /// A module exporting an empty function under each of `exports`, importing `imports`.
+    fn test_module(exports: &[&str], imports: &[(&str, &str)]) -> Vec<u8> {
+        let mut module = Module::with_config(ModuleConfig::new());
+        let ty = module.types.add(&[], &[]);
+        for (module_name, name) in imports {
+            module.add_import_func(module_name, name, ty);
+        }
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body();
+        let function = builder.finish(vec![], &mut module.funcs);
+        for name in exports {
+            module.exports.add(name, function);
+        }
+        module.emit_wasm()
+    }
+
+    fn assert_invalid(result: Result<()>, expected: &str) {
+        match result {
+            Err(Error::InvalidWasmModule(reason)) => assert!(
+                reason.contains(expected),
+                "`{reason}` should contain `{expected}`"
+            ),
+            r => panic!("Invalid result, should fail with `InvalidWasmModule`: {r:?}"),
+        }
+    }
+
+    #[test]
+    fn accepts_a_module_exporting_canister_methods() {
+        let module = test_module(
+            &["canister_init", "canister_query get", "canister_update set"],
+            &[("ic0", "msg_reply")],
+        );
+        assert!(validate_wasm_module(&module, u64::MAX).is_ok());
+    }
+
+    #[test]
+    fn rejects_a_module_above_the_maximum_size() {
+        let module = test_module(&["canister_query get"], &[]);
+        let size = module.len() as u64;
+        assert!(validate_wasm_module(&module, size).is_ok());
+        assert!(matches!(
+            validate_wasm_module(&module, size - 1),
+            Err(Error::WasmModuleTooLarge { size: s, max }) if s == size && max == size - 1
+        ));
+    }
+
+    #[test]
+    fn rejects_bytes_that_are_not_a_wasm_module() {
+        assert_invalid(
+            validate_wasm_module(b"not a wasm module", u64::MAX),
+            "magic header",
+        );
+
+        let module = test_module(&["canister_query get"], &[]);
+        let mut other_version = module.clone();
+        other_version[4] = 0x02;
+        assert_invalid(
+            validate_wasm_module(&other_version, u64::MAX),
+            "Unsupported wasm version",
+        );
+
+        let truncated = &module[..module.len() - 1];
+        assert!(matches!(
+            validate_wasm_module(truncated, u64::MAX),
+            Err(Error::InvalidWasmModule(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_a_module_without_canister_methods() {
+        assert_invalid(
+            validate_wasm_module(&test_module(&[], &[]), u64::MAX),
+            "does not export any canister methods",
+        );
+        assert_invalid(
+            validate_wasm_module(&test_module(&["canister_init"], &[]), u64::MAX),
+            "does not export any canister methods",
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_system_exports() {
+        assert_invalid(
+            validate_wasm_module(
+                &test_module(&["canister_query get", "canister_unknown"], &[]),
+                u64::MAX,
+            ),
+            "`canister_unknown` is not a valid system export",
+        );
+    }
+
+    #[test]
+    fn accepts_ic0_imports_of_newer_cdks() {
+        let module = test_module(
+            &["canister_query get"],
+            &[
+                ("ic0", "msg_deadline"),
+                ("ic0", "call_with_best_effort_response"),
+            ],
+        );
+        assert!(validate_wasm_module(&module, u64::MAX).is_ok());
+    }
+
+    #[test]
+    fn rejects_imports_used_by_the_controller_with_another_signature() {
+        // `ic0.time` returns the time, the test imports return nothing.
+        assert_invalid(
+            validate_wasm_module(
+                &test_module(&["canister_query get"], &[("ic0", "time")]),
+                u64::MAX,
+            ),
+            "`ic0.time` does not have the signature",
+        );
+    }
+
+    #[test]
+    fn rejects_imports_other_than_the_allowed_ic0_functions() {
+        assert_invalid(
+            validate_wasm_module(
+                &test_module(&["canister_query get"], &[("env", "msg_reply")]),
+                u64::MAX,
+            ),
+            "only `ic0` imports are supported",
+        );
+        assert_invalid(
+            validate_wasm_module(
+                &test_module(&["canister_query get"], &[("ic0", "mint_cycles")]),
+                u64::MAX,
+            ),
+            "`ic0.mint_cycles` is not allowed",
+        );
+    }
+}
@@ -0,0 +1,123 @@
+use std::time::Duration;
+
+use candid::CandidType;
+use candid::Deserialize;
+use ic_ledger_types::Subaccount;
+use ic_ledger_types::Tokens;
+
+use crate::developer::DeveloperID;
+use crate::guard::OperationGuard;
+use crate::journal::Operation;
+use crate::memory::STATE;
+use crate::schema::Versioned;
+use crate::utils::transfer_tokens;
+use crate::Result;
+
+pub const SETTLEMENT_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+// The ledger only deduplicates transfers created in the last 24 hours, a commission still unpaid
+// after this long is retried as a new transfer, any earlier attempt having been rejected for good.
+pub const MAX_OPERATION_AGE_NANOS: u64 = 23 * 60 * 60 * 1_000_000_000;
+
+/// A commission the escrow account of a developer could not pay when it was charged, retried by
+/// the settlement timer with the same operation until it is paid.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct UnpaidCommission {
+    pub amount: Tokens,
+    pub operation: Operation,
+}
+
+impl Versioned for UnpaidCommission {
+    const NAME: &'static str = "unpaid commission";
+    const VERSION: u8 = 1;
+}
+
+/// Platform commission on a charge of `amount`, rounded up to the next e8.
+pub fn commission_of(amount: Tokens) -> Tokens {
+    STATE.with_borrow(|s| s.settings().commission_of(amount))
+}
+
+/// Transfer the platform commission on a charge of `amount` from the developer escrow account to
+/// the treasury account, returning the commission. A commission that can not be transferred is
+/// kept as unpaid and settled later.
+pub async fn charge_commission(
+    developer_id: DeveloperID,
+    from_subaccount: Subaccount,
+    amount: Tokens,
+) -> Tokens {
+    let commission = commission_of(amount);
+    pay_commission(developer_id, from_subaccount, commission).await;
+    commission
+}
+
+pub async fn pay_commission(
+    developer_id: DeveloperID,
+    from_subaccount: Subaccount,
+    commission: Tokens,
+) {
+    let operation = Operation::new();
+    if transfer_commission(from_subaccount, commission, operation)
+        .await
+        .is_err()
+    {
+        STATE.with_borrow_mut(|s| {
+            s.insert_unpaid_commission(
+                developer_id,
+                UnpaidCommission {
+                    amount: commission,
+                    operation,
+                },
+            )
+        });
+    }
+}
+
+async fn transfer_commission(
+    from_subaccount: Subaccount,
+    commission: Tokens,
+    operation: Operation,
+) -> Result<()> {
+    if commission == Tokens::from_e8s(0) {
+        return Ok(());
+    }
+
+    let treasury_account = STATE.with_borrow(|s| s.settings().treasury_account);
+    transfer_tokens(from_subaccount, treasury_account, commission, operation)
+        .await
+        .map(|_| ())
+}
+
+/// Periodically retry the commissions that could not be paid when they were charged.
+pub fn start_settlement_timer() {
+    ic_cdk_timers::set_timer_interval(SETTLEMENT_INTERVAL, || {
+        ic_cdk::spawn(settle_unpaid_commissions())
+    });
+}
+
+async fn settle_unpaid_commissions() {
+    let unpaid_commissions = STATE.with_borrow(|s| s.get_unpaid_commissions());
+    for (developer_id, memo, commission) in unpaid_commissions {
+        // Settled with the next run when the developer is spending from their escrow right now.
+        let Ok(_guard) = OperationGuard::new(developer_id) else {
+            continue;
+        };
+        let Ok(developer) = STATE.with_borrow(|s| s.get_developer(&developer_id)) else {
+            continue;
+        };
+
+        let mut operation = commission.operation;
+        if ic_cdk::api::time().saturating_sub(operation.created_at_time) > MAX_OPERATION_AGE_NANOS {
+            operation = Operation::with_memo(memo);
+            STATE.with_borrow_mut(|s| {
+                s.update_unpaid_commission(developer_id, memo, |c| c.operation = operation)
+            });
+        }
+
+        if transfer_commission(developer.escrow_account, commission.amount, operation)
+            .await
+            .is_ok()
+        {
+            STATE.with_borrow_mut(|s| s.remove_unpaid_commission(developer_id, memo));
+        }
+    }
+}
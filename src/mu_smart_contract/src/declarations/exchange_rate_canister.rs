@@ -0,0 +1,61 @@
+// Bindings of `exchange_rate_canister.did`, generated with ic-cdk-bindgen. The canister ID and
+// the service wrapper are left out, the canister is called at `exchange_rate_canister_id` of the
+// settings.
+#![allow(dead_code, unused_imports)]
+use candid::{self, CandidType, Deserialize, Principal, Encode, Decode};
+
+#[derive(Debug, PartialEq, CandidType, Deserialize)]
+pub enum AssetClass { Cryptocurrency, FiatCurrency }
+
+#[derive(Debug, PartialEq, CandidType, Deserialize)]
+pub struct Asset { pub class: AssetClass, pub symbol: String }
+
+#[derive(Debug, PartialEq, CandidType, Deserialize)]
+pub struct GetExchangeRateRequest {
+  pub timestamp: Option<u64>,
+  pub quote_asset: Asset,
+  pub base_asset: Asset,
+}
+
+#[derive(Debug, PartialEq, CandidType, Deserialize)]
+pub struct ExchangeRateMetadata {
+  pub decimals: u32,
+  pub forex_timestamp: Option<u64>,
+  pub quote_asset_num_received_rates: u64,
+  pub base_asset_num_received_rates: u64,
+  pub base_asset_num_queried_sources: u64,
+  pub standard_deviation: u64,
+  pub quote_asset_num_queried_sources: u64,
+}
+
+#[derive(Debug, PartialEq, CandidType, Deserialize)]
+pub struct ExchangeRate {
+  pub metadata: ExchangeRateMetadata,
+  pub rate: u64,
+  pub timestamp: u64,
+  pub quote_asset: Asset,
+  pub base_asset: Asset,
+}
+
+#[derive(Debug, PartialEq, CandidType, Deserialize)]
+pub enum ExchangeRateError {
+  AnonymousPrincipalNotAllowed,
+  CryptoQuoteAssetNotFound,
+  FailedToAcceptCycles,
+  ForexBaseAssetNotFound,
+  CryptoBaseAssetNotFound,
+  StablecoinRateTooFewRates,
+  ForexAssetsNotFound,
+  InconsistentRatesReceived,
+  RateLimited,
+  StablecoinRateZeroRate,
+  Other{ code: u32, description: String },
+  ForexInvalidTimestamp,
+  NotEnoughCycles,
+  ForexQuoteAssetNotFound,
+  StablecoinRateNotFound,
+  Pending,
+}
+
+#[derive(Debug, PartialEq, CandidType, Deserialize)]
+pub enum GetExchangeRateResult { Ok(ExchangeRate), Err(ExchangeRateError) }
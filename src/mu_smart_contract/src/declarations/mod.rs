@@ -0,0 +1,5 @@
+#![allow(unused_imports)]
+#![allow(non_upper_case_globals)]
+#![allow(non_snake_case)]
+#[rustfmt::skip]
+pub mod exchange_rate_canister;
@@ -1,21 +1,26 @@
-use std::borrow::Cow;
-
 use candid::CandidType;
-use candid::Decode;
 use candid::Deserialize;
-use candid::Encode;
 use candid::Principal;
 use ic_cdk::api::management_canister::main::raw_rand;
 use ic_ledger_types::AccountIdentifier;
-use ic_ledger_types::Memo;
 use ic_ledger_types::Subaccount;
-use ic_stable_structures::storable::Bound;
-use ic_stable_structures::Storable;
+use ic_ledger_types::Tokens;
+use ic_ledger_types::DEFAULT_FEE;
+use icrc_ledger_types::icrc1::account::Account;
 
-use crate::app::AppID;
+use crate::commission::MAX_OPERATION_AGE_NANOS;
 use crate::error::Error;
+use crate::guard::OperationGuard;
+use crate::journal::journal_entry;
+use crate::journal::run_once;
+use crate::journal::Operation;
 use crate::memory::STATE;
+use crate::schema;
+use crate::schema::decode;
+use crate::schema::Versioned;
 use crate::utils::get_developer_escrow_balance;
+use crate::utils::icrc1_transfer_tokens;
+use crate::utils::icrc2_transfer_from_tokens;
 use crate::utils::transfer_tokens;
 use crate::Result;
 
@@ -24,13 +29,53 @@ pub type DeveloperID = Principal;
 #[derive(CandidType, Deserialize)]
 pub struct Developer {
     pub(crate) escrow_account: Subaccount,
-    pub(crate) apps: Vec<AppID>,
+    // Maximum amount drawn from the developer's own account, within the ICRC-2 allowance they
+    // approved, when the escrow is short for a cycles request.
+    pub(crate) standing_allowance: Option<Tokens>,
+}
+
+/// Cycles held by this canister for a developer, paid from their escrow account for an app
+/// canister that could not be created. The next deploy of the developer spends them first.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct DeployCredit {
+    pub cycles: u128,
+    // Tokens paid for the cycles, the commission is charged once they are spent on a canister.
+    pub amount: Tokens,
+}
+
+impl Default for DeployCredit {
+    fn default() -> Self {
+        Self {
+            cycles: 0,
+            amount: Tokens::from_e8s(0),
+        }
+    }
+}
+
+impl Versioned for DeployCredit {
+    const NAME: &'static str = "deploy credit";
+    const VERSION: u8 = 1;
 }
 
 impl Developer {
-    pub fn as_dto(&self) -> crate::developer::dto::DeveloperDto {
+    pub fn as_dto(
+        &self,
+        deploy_credit: Option<DeployCredit>,
+        unpaid_commission: Tokens,
+    ) -> crate::developer::dto::DeveloperDto {
         dto::DeveloperDto {
             escrow_account: AccountIdentifier::new(&ic_cdk::id(), &self.escrow_account),
+            escrow_icrc1_account: self.escrow_icrc1_account(),
+            standing_allowance: self.standing_allowance,
+            deploy_credit,
+            unpaid_commission,
+        }
+    }
+
+    pub fn escrow_icrc1_account(&self) -> Account {
+        Account {
+            owner: ic_cdk::id(),
+            subaccount: Some(self.escrow_account.0),
         }
     }
 
@@ -65,9 +110,80 @@ impl Developer {
         }
     }
 
-    pub fn ensure_developer_has_budget_for_new_app(&self) -> Result<()> {
-        let apps_count = self.apps.len();
-        if apps_count > STATE.with_borrow(|s| s.settings().max_apps_per_developer) {
+    /// Top up the escrow account from the standing allowance, if it has less than `needed` tokens.
+    /// Fails when the tokens missing, plus the fee of the pull, are more than what is left of the
+    /// allowance.
+    pub async fn ensure_escrow_has_balance_from_standing_allowance(
+        &self,
+        developer_id: DeveloperID,
+        needed: Tokens,
+    ) -> Result<()> {
+        let Some(standing_allowance) = self.standing_allowance else {
+            return Ok(());
+        };
+
+        let escrow_balance = get_developer_escrow_balance(&self.escrow_account).await?;
+        if escrow_balance >= needed {
+            return Ok(());
+        }
+        let shortfall = needed - escrow_balance;
+        // The fee of the pull is drawn from the developer's approval too.
+        let drawn = shortfall + DEFAULT_FEE;
+        if drawn > standing_allowance {
+            return Err(Error::InsufficientEscrowBalance {
+                was: escrow_balance,
+                needed,
+            });
+        }
+
+        // Journaled by amount, so a pull that failed is replayed with its operation when the same
+        // amount is pulled again, and executed at most once by the ledger.
+        let (key, entry) = journal_entry(
+            developer_id,
+            "standing_allowance_pull",
+            &shortfall.e8s().to_string(),
+            &shortfall,
+        )?;
+        let expired = ic_cdk::api::time().saturating_sub(entry.operation.created_at_time)
+            > MAX_OPERATION_AGE_NANOS;
+        let operation = if entry.completed || expired {
+            STATE
+                .with_borrow_mut(|s| s.begin_journal_entry(key, Operation::new(), entry.args_hash))
+                .operation
+        } else {
+            entry.operation
+        };
+
+        let from = Account {
+            owner: developer_id,
+            subaccount: None,
+        };
+        let block_index = icrc2_transfer_from_tokens(
+            from,
+            self.escrow_icrc1_account(),
+            shortfall.e8s().into(),
+            operation,
+        )
+        .await?;
+        // What is drawn is taken off the allowance, until the developer sets it again.
+        STATE.with_borrow_mut(|s| {
+            s.complete_journal_entry(&key, Some(block_index));
+            s.update_developer(developer_id, |d| {
+                d.standing_allowance = d
+                    .standing_allowance
+                    .map(|allowance| Tokens::from_e8s(allowance.e8s().saturating_sub(drawn.e8s())))
+            })
+        })
+    }
+
+    pub fn ensure_developer_has_budget_for_new_app(developer_id: &DeveloperID) -> Result<()> {
+        let (apps_count, max_apps_count) = STATE.with_borrow(|s| {
+            (
+                s.apps_count(developer_id),
+                s.settings().max_apps_per_developer,
+            )
+        });
+        if apps_count >= max_apps_count {
             Err(Error::MaxAppsCountReached)
         } else {
             Ok(())
@@ -90,7 +206,7 @@ async fn register_developer() -> Result<crate::developer::DeveloperID> {
 
     let developer = Developer {
         escrow_account,
-        apps: Vec::new(),
+        standing_allowance: None,
     };
 
     let _ = STATE.with_borrow_mut(|s| s.register_developer(developer_id, developer));
@@ -99,29 +215,100 @@ async fn register_developer() -> Result<crate::developer::DeveloperID> {
 
 #[ic_cdk::query]
 fn get_developer() -> Result<crate::developer::dto::DeveloperDto> {
-    let (_, developer) = Developer::get_caller_developer_account()?;
-    Ok(developer.as_dto())
+    let (developer_id, developer) = Developer::get_caller_developer_account()?;
+    let deploy_credit = STATE.with_borrow(|s| s.get_deploy_credit(&developer_id))?;
+    let unpaid_commission = STATE.with_borrow(|s| s.unpaid_commission_of(&developer_id))?;
+    Ok(developer.as_dto(deploy_credit, unpaid_commission))
 }
 
+// Withdrawals and funding take an optional idempotency key, retrying with the same key never
+// transfers twice and returns the block of the original transfer.
+
 #[ic_cdk::update]
 async fn request_escrow_withdraw(
     to: ic_ledger_types::AccountIdentifier,
     amount: ic_ledger_types::Tokens,
+    idempotency_key: Option<String>,
 ) -> Result<ic_ledger_types::BlockIndex> {
-    let (_, developer) = Developer::get_caller_developer_account()?;
-    transfer_tokens(developer.escrow_account, to, amount, Memo(0)).await
+    let (developer_id, developer) = Developer::get_caller_developer_account()?;
+    let _guard = OperationGuard::new(developer_id)?;
+    let args = (to, amount);
+    run_once(
+        developer_id,
+        "request_escrow_withdraw",
+        idempotency_key,
+        &args,
+        |operation| transfer_tokens(developer.escrow_account, to, amount, operation),
+    )
+    .await
 }
 
-impl Storable for Developer {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
+#[ic_cdk::update]
+async fn request_escrow_withdraw_icrc1(
+    to: icrc_ledger_types::icrc1::account::Account,
+    amount: candid::Nat,
+    idempotency_key: Option<String>,
+) -> Result<candid::Nat> {
+    let (developer_id, developer) = Developer::get_caller_developer_account()?;
+    let _guard = OperationGuard::new(developer_id)?;
+    let args = (to, amount.clone());
+    run_once(
+        developer_id,
+        "request_escrow_withdraw_icrc1",
+        idempotency_key,
+        &args,
+        |operation| icrc1_transfer_tokens(developer.escrow_account, to, amount, operation),
+    )
+    .await
+    .map(candid::Nat::from)
+}
 
-    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+/// Move `amount` tokens from the caller's account into their escrow account, the caller should
+/// have approved this canister to spend at least `amount` plus the transfer fee.
+#[ic_cdk::update]
+async fn fund_escrow(amount: candid::Nat, idempotency_key: Option<String>) -> Result<candid::Nat> {
+    let (developer_id, developer) = Developer::get_caller_developer_account()?;
+    let _guard = OperationGuard::new(developer_id)?;
+    let from = Account {
+        owner: developer_id,
+        subaccount: None,
+    };
+    let args = amount.clone();
+    run_once(
+        developer_id,
+        "fund_escrow",
+        idempotency_key,
+        &args,
+        |operation| {
+            icrc2_transfer_from_tokens(from, developer.escrow_icrc1_account(), amount, operation)
+        },
+    )
+    .await
+    .map(candid::Nat::from)
+}
 
-    const BOUND: Bound = Bound::Unbounded;
+/// Allow cycles requests of the caller's apps to draw up to `max_amount` from the caller's
+/// account when the escrow is short, `None` disables it.
+#[ic_cdk::update]
+fn set_standing_allowance(max_amount: Option<ic_ledger_types::Tokens>) -> Result<()> {
+    let (developer_id, _) = Developer::get_caller_developer_account()?;
+    STATE.with_borrow_mut(|s| {
+        s.update_developer(developer_id, |d| d.standing_allowance = max_amount)
+    })
+}
+
+impl Versioned for Developer {
+    const NAME: &'static str = "developer";
+    const VERSION: u8 = 2;
+
+    fn migrate(version: u8, payload: &[u8]) -> Result<Self> {
+        match version {
+            1 => decode::<Self, schema::v1::Developer>(payload).map(Self::from),
+            _ => Err(Error::Internal(format!(
+                "Unknown developer record version {version}"
+            ))),
+        }
+    }
 }
 
 pub mod dto {
@@ -129,6 +316,12 @@ pub mod dto {
 
     #[derive(CandidType, Deserialize)]
     pub struct DeveloperDto {
+        // Legacy account identifier of `escrow_icrc1_account`.
         pub escrow_account: AccountIdentifier,
+        pub escrow_icrc1_account: Account,
+        pub standing_allowance: Option<Tokens>,
+        pub deploy_credit: Option<DeployCredit>,
+        // Commission that could not be transferred yet, settled in the background.
+        pub unpaid_commission: Tokens,
     }
 }
@@ -1,4 +1,5 @@
 use candid::CandidType;
+use ic_ledger_types::Timestamp;
 use ic_ledger_types::Tokens;
 
 #[derive(CandidType, Debug)]
@@ -8,5 +9,69 @@ pub enum Error {
     DeveloperAccountNotFound,
     DeveloperAccountAlreadyExist,
     MaxAppsCountReached,
-    InsufficientBalanceForDeploy { was: Tokens, needed: Tokens },
+    InsufficientBalanceForDeploy {
+        was: Tokens,
+        needed: Tokens,
+    },
+    // The escrow account, along with the standing allowance, can not pay for the operation.
+    InsufficientEscrowBalance {
+        was: Tokens,
+        needed: Tokens,
+    },
+    UploadNotFound,
+    UploadSizeLimitExceeded {
+        max: u64,
+    },
+    // Uploads in progress or not deployed yet, cancel one or wait for it to expire.
+    TooManyUploads {
+        max: u64,
+    },
+    UploadIncomplete {
+        received: u64,
+        expected: u64,
+    },
+    UploadHashMismatch,
+    UploadIsFinalized,
+    UploadIsNotFinalized,
+    InvalidAppPackage(String),
+    InvalidWasmModule(String),
+    WasmModuleTooLarge {
+        size: u64,
+        max: u64,
+    },
+    Unauthorized,
+    InvalidSettings(String),
+    InvalidIdempotencyKey,
+    // The idempotency key was already used for the same operation with other arguments.
+    IdempotencyKeyConflict,
+    // The top-up is paid but not converted into cycles yet, it is retried later.
+    TopUpPending {
+        block_index: u64,
+    },
+    TopUpRefunded {
+        reason: String,
+    },
+    // Another operation on the same escrow account is not finished yet.
+    OperationInProgress,
+    ExchangeRateUnavailable,
+    CyclesRequestAboveLimit {
+        max: u64,
+    },
+    CyclesRequestTooSoon {
+        next_request_at: Timestamp,
+    },
+    DailyCyclesLimitReached {
+        remaining: u64,
+    },
+    InvalidBudget(String),
+    AppBudgetExceeded {
+        remaining: Tokens,
+    },
+    // The usages of the app are still being moved after an upgrade, retry shortly.
+    AppUsagesMigrating,
+    // Cycles per ICP of both sources, nothing is charged until they agree again.
+    ExchangeRatesDiverge {
+        exchange_rate_canister: u64,
+        cycles_minting_canister: u64,
+    },
 }
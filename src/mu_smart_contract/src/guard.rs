@@ -0,0 +1,44 @@
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::thread::LocalKey;
+
+use crate::developer::DeveloperID;
+use crate::error::Error;
+use crate::Result;
+
+thread_local! {
+    static DEVELOPERS_IN_OPERATION: RefCell<BTreeSet<DeveloperID>> = RefCell::default();
+}
+
+/// Holds a key in a set of keys in use, until it is dropped.
+pub struct Guard<K: Ord + Clone + 'static> {
+    keys: &'static LocalKey<RefCell<BTreeSet<K>>>,
+    key: K,
+}
+
+impl<K: Ord + Clone + 'static> Guard<K> {
+    /// `None` when the key is already in use.
+    pub fn new(keys: &'static LocalKey<RefCell<BTreeSet<K>>>, key: K) -> Option<Self> {
+        keys.with_borrow_mut(|k| k.insert(key.clone()))
+            .then_some(Self { keys, key })
+    }
+}
+
+// Also dropped when the call traps, as the future is cleaned up by the cdk.
+impl<K: Ord + Clone + 'static> Drop for Guard<K> {
+    fn drop(&mut self) {
+        self.keys.with_borrow_mut(|k| k.remove(&self.key));
+    }
+}
+
+/// Held while an operation spends from a developer's escrow, so concurrent operations of the same
+/// developer, or of their apps, can not act on a balance or quota checked before an await.
+pub struct OperationGuard(Guard<DeveloperID>);
+
+impl OperationGuard {
+    pub fn new(developer_id: DeveloperID) -> Result<Self> {
+        Guard::new(&DEVELOPERS_IN_OPERATION, developer_id)
+            .map(Self)
+            .ok_or(Error::OperationInProgress)
+    }
+}
@@ -0,0 +1,134 @@
+use std::future::Future;
+
+use candid::CandidType;
+use candid::Deserialize;
+use candid::Encode;
+use candid::Principal;
+use ic_ledger_types::BlockIndex;
+use sha2::Digest;
+use sha2::Sha256;
+
+use crate::error::Error;
+use crate::memory::STATE;
+use crate::schema::Versioned;
+use crate::Result;
+
+pub type JournalKey = [u8; 32];
+
+const MAX_IDEMPOTENCY_KEY_LENGTH: usize = 64;
+
+// The ledger deduplicates transfers created in the last 24 hours, entries are kept a bit longer
+// so a retry always either replays the transfer or gets rejected as too old.
+pub const JOURNAL_RETENTION_NANOS: u64 = 25 * 60 * 60 * 1_000_000_000;
+
+/// Memo and creation time of a ledger transfer, the ledger rejects transfers with the same
+/// arguments as duplicates.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+pub struct Operation {
+    pub memo: u64,
+    pub created_at_time: u64,
+}
+
+impl Operation {
+    /// A new operation with a unique memo.
+    pub fn new() -> Self {
+        Self::with_memo(STATE.with_borrow_mut(|s| s.next_operation_id()))
+    }
+
+    /// A new operation with a fixed memo, for transfers that are recognized by their memo.
+    pub fn with_memo(memo: u64) -> Self {
+        Self {
+            memo,
+            created_at_time: ic_cdk::api::time(),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct JournalEntry {
+    pub operation: Operation,
+    // Hash of the arguments of the operation, a key is only replayed with the same arguments.
+    pub args_hash: [u8; 32],
+    // Set once the transfer is executed.
+    pub block_index: Option<BlockIndex>,
+    // Whether the operation is done, including when it had nothing to transfer.
+    pub completed: bool,
+}
+
+impl Versioned for JournalEntry {
+    const NAME: &'static str = "journal entry";
+    const VERSION: u8 = 1;
+}
+
+// Length-prefixed, so different operation names and keys never hash the same.
+fn journal_key(caller: &Principal, operation_name: &str, idempotency_key: &str) -> JournalKey {
+    let mut hasher = Sha256::new();
+    hasher.update(caller.as_slice());
+    hasher.update((operation_name.len() as u64).to_be_bytes());
+    hasher.update(operation_name.as_bytes());
+    hasher.update(idempotency_key.as_bytes());
+    hasher.finalize().into()
+}
+
+fn args_hash(args: &impl CandidType) -> Result<[u8; 32]> {
+    let bytes = Encode!(args)
+        .map_err(|e| Error::Internal(format!("Failed to encode the arguments, reason: {e}")))?;
+    Ok(Sha256::digest(bytes).into())
+}
+
+/// Journal entry of `caller` for `operation_name` and `idempotency_key`, begun with a new
+/// operation when there is none yet. Reusing a key with different `args` fails with
+/// `IdempotencyKeyConflict`.
+pub fn journal_entry(
+    caller: Principal,
+    operation_name: &str,
+    idempotency_key: &str,
+    args: &impl CandidType,
+) -> Result<(JournalKey, JournalEntry)> {
+    if idempotency_key.is_empty() || idempotency_key.len() > MAX_IDEMPOTENCY_KEY_LENGTH {
+        return Err(Error::InvalidIdempotencyKey);
+    }
+
+    let key = journal_key(&caller, operation_name, idempotency_key);
+    let args_hash = args_hash(args)?;
+    match STATE.with_borrow(|s| s.get_journal_entry(&key))? {
+        Some(entry) if entry.args_hash != args_hash => Err(Error::IdempotencyKeyConflict),
+        Some(entry) => Ok((key, entry)),
+        None => {
+            let operation = Operation::new();
+            let entry = STATE.with_borrow_mut(|s| s.begin_journal_entry(key, operation, args_hash));
+            Ok((key, entry))
+        }
+    }
+}
+
+/// Run the `transfer` of `caller` at most once for each `operation_name` and `idempotency_key`.
+///
+/// Retries with the same key replay the transfer with the original memo and creation time, so the
+/// ledger either executes it for the first time or returns the block of the original transfer.
+/// Reusing a key with different `args` fails with `IdempotencyKeyConflict`.
+pub async fn run_once<F, Fut>(
+    caller: Principal,
+    operation_name: &str,
+    idempotency_key: Option<String>,
+    args: &impl CandidType,
+    transfer: F,
+) -> Result<BlockIndex>
+where
+    F: FnOnce(Operation) -> Fut,
+    Fut: Future<Output = Result<BlockIndex>>,
+{
+    let Some(idempotency_key) = idempotency_key else {
+        return transfer(Operation::new()).await;
+    };
+
+    let (key, entry) = journal_entry(caller, operation_name, &idempotency_key, args)?;
+    if let Some(block_index) = entry.block_index {
+        return Ok(block_index);
+    }
+
+    // The entry is kept when the transfer fails, as it may have been executed anyway.
+    let block_index = transfer(entry.operation).await?;
+    STATE.with_borrow_mut(|s| s.complete_journal_entry(&key, Some(block_index)));
+    Ok(block_index)
+}
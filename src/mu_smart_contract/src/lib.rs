@@ -1,11 +1,19 @@
 type Result<T> = std::result::Result<T, error::Error>;
 
 mod app;
+mod commission;
 mod declarations;
 mod developer;
 mod error;
+mod guard;
+mod journal;
 mod memory;
+mod notification;
+mod schema;
 pub mod settings;
+mod top_up;
+mod upload;
+mod usage;
 mod utils;
 
 ic_cdk::export_candid!();
@@ -1,25 +1,73 @@
 use std::cell::RefCell;
-use std::sync::OnceLock;
-use std::time::Instant;
+use std::ops::Bound;
 
+use candid::Principal;
+use ic_ledger_types::BlockIndex;
+use ic_ledger_types::Timestamp;
+use ic_ledger_types::Tokens;
 use ic_stable_structures::memory_manager::MemoryId;
 use ic_stable_structures::memory_manager::MemoryManager;
 use ic_stable_structures::memory_manager::VirtualMemory;
 use ic_stable_structures::BTreeMap;
 use ic_stable_structures::DefaultMemoryImpl;
+use ic_stable_structures::StableCell;
+use sha2::Digest;
+use sha2::Sha256;
 
+use crate::app::budget::AppBudget;
+use crate::app::package::AppPackage;
 use crate::app::App;
 use crate::app::AppID;
+use crate::app::AppState;
 use crate::app::AppUsage;
+use crate::commission::UnpaidCommission;
+use crate::developer::DeployCredit;
 use crate::developer::Developer;
 use crate::developer::DeveloperID;
 use crate::error::Error;
+use crate::journal::JournalEntry;
+use crate::journal::JournalKey;
+use crate::journal::Operation;
+use crate::journal::JOURNAL_RETENTION_NANOS;
+use crate::notification::Notification;
+use crate::notification::NotificationID;
+use crate::notification::NotificationKind;
+use crate::notification::MAX_NOTIFICATIONS_PER_DEVELOPER;
+use crate::schema::v1;
+use crate::schema::Stored;
+use crate::schema::Versioned;
 use crate::settings::Settings;
+use crate::settings::SettingsUpdate;
+use crate::top_up::PendingTopUp;
+use crate::top_up::TopUpStatus;
+use crate::upload::is_upload_expired;
+use crate::upload::Upload;
+use crate::upload::UploadID;
+use crate::usage::UnpaidUsage;
+use crate::utils::exchange::IcpCyclesRate;
 use crate::Result;
 
 // A new memory should be created for every additional stable structure.
 const USERS_BTREE: MemoryId = MemoryId::new(0);
 const APPS_BTREE: MemoryId = MemoryId::new(1);
+const UPLOADS_BTREE: MemoryId = MemoryId::new(2);
+const UPLOAD_CHUNKS_BTREE: MemoryId = MemoryId::new(3);
+const SETTINGS_CELL: MemoryId = MemoryId::new(4);
+const ADMINS_BTREE: MemoryId = MemoryId::new(5);
+const OPERATION_ID_CELL: MemoryId = MemoryId::new(6);
+const JOURNAL_BTREE: MemoryId = MemoryId::new(7);
+const JOURNAL_EXPIRY_BTREE: MemoryId = MemoryId::new(8);
+const PENDING_TOP_UPS_BTREE: MemoryId = MemoryId::new(9);
+const ICP_CYCLES_RATE_CELL: MemoryId = MemoryId::new(10);
+const NOTIFICATIONS_BTREE: MemoryId = MemoryId::new(11);
+const USAGES_BTREE: MemoryId = MemoryId::new(12);
+const DEVELOPER_APPS_BTREE: MemoryId = MemoryId::new(13);
+const DEPLOY_CREDITS_BTREE: MemoryId = MemoryId::new(14);
+const UNPAID_COMMISSIONS_BTREE: MemoryId = MemoryId::new(15);
+const UPLOAD_ID_CELL: MemoryId = MemoryId::new(16);
+const DEVELOPER_UPLOADS_BTREE: MemoryId = MemoryId::new(17);
+const NOTIFICATION_ID_CELL: MemoryId = MemoryId::new(18);
+const UNPAID_USAGES_BTREE: MemoryId = MemoryId::new(19);
 
 pub type Memory = VirtualMemory<DefaultMemoryImpl>;
 
@@ -38,40 +86,183 @@ fn get_apps_btree_memory() -> Memory {
     MEMORY_MANAGER.with(|m| m.borrow().get(APPS_BTREE))
 }
 
-pub struct State {
-    settings: OnceLock<Settings>,
-    developers: BTreeMap<DeveloperID, Developer, Memory>,
+fn get_uploads_btree_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(UPLOADS_BTREE))
+}
+
+fn get_upload_chunks_btree_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(UPLOAD_CHUNKS_BTREE))
+}
+
+fn get_settings_cell_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(SETTINGS_CELL))
+}
+
+fn get_admins_btree_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(ADMINS_BTREE))
+}
+
+fn get_operation_id_cell_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(OPERATION_ID_CELL))
+}
+
+fn get_journal_btree_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(JOURNAL_BTREE))
+}
+
+fn get_journal_expiry_btree_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(JOURNAL_EXPIRY_BTREE))
+}
+
+fn get_pending_top_ups_btree_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(PENDING_TOP_UPS_BTREE))
+}
+
+fn get_icp_cycles_rate_cell_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(ICP_CYCLES_RATE_CELL))
+}
+
+fn get_notifications_btree_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(NOTIFICATIONS_BTREE))
+}
+
+fn get_usages_btree_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(USAGES_BTREE))
+}
+
+fn get_developer_apps_btree_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(DEVELOPER_APPS_BTREE))
+}
+
+fn get_deploy_credits_btree_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(DEPLOY_CREDITS_BTREE))
+}
+
+fn get_unpaid_commissions_btree_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(UNPAID_COMMISSIONS_BTREE))
+}
+
+fn get_upload_id_cell_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(UPLOAD_ID_CELL))
+}
+
+fn get_developer_uploads_btree_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(DEVELOPER_UPLOADS_BTREE))
+}
+
+fn get_notification_id_cell_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(NOTIFICATION_ID_CELL))
+}
+
+fn get_unpaid_usages_btree_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(UNPAID_USAGES_BTREE))
+}
+
+// Usages of an app by their timestamp, and their order among the ones with the same timestamp.
+type UsageKey = (AppID, u64, u64);
+
+/// Timestamp and sequence number of a usage, among the usages of its app.
+pub type UsagePosition = (u64, u64);
+
+pub type AppsOfDeveloper = Vec<(AppID, App)>;
 
-    // TODO: Refactor when there is support for nested structure in `ic_stable_structures`.
-    // See: https://github.com/dfinity/stable-structures/issues/215#issuecomment-2090315537
-    apps: BTreeMap<AppID, App, Memory>,
-    pub icp_cycles_exchange_rate: Option<(u64, Instant)>,
+pub struct State {
+    settings: StableCell<Option<Stored<Settings>>, Memory>,
+    // Decoded once, as most calls read the settings.
+    cached_settings: Option<Settings>,
+    admins: BTreeMap<Principal, (), Memory>,
+    developers: BTreeMap<DeveloperID, Stored<Developer>, Memory>,
+    apps: BTreeMap<AppID, Stored<App>, Memory>,
+    // Apps of each developer, in place of a list in the developer record.
+    developer_apps: BTreeMap<(DeveloperID, AppID), (), Memory>,
+    uploads: BTreeMap<UploadID, Stored<Upload>, Memory>,
+    upload_chunks: BTreeMap<(UploadID, u32), Vec<u8>, Memory>,
+    // Creation time of the uploads of each developer, to count them without reading every upload.
+    developer_uploads: BTreeMap<(DeveloperID, UploadID), u64, Memory>,
+    // Upload IDs are never reused, so they are also in the order the uploads were created.
+    next_upload_id: StableCell<u64, Memory>,
+    next_operation_id: StableCell<u64, Memory>,
+    journal: BTreeMap<JournalKey, Stored<JournalEntry>, Memory>,
+    // Journal entries by their creation time, to drop them once the ledger forgets the transfers.
+    journal_expiry: BTreeMap<(u64, JournalKey), (), Memory>,
+    pending_top_ups: BTreeMap<BlockIndex, Stored<PendingTopUp>, Memory>,
+    icp_cycles_rate: StableCell<Option<Stored<IcpCyclesRate>>, Memory>,
+    notifications: BTreeMap<(DeveloperID, NotificationID), Stored<Notification>, Memory>,
+    // Notification IDs are never reused, so cleared notifications are not confused with new ones.
+    next_notification_id: StableCell<NotificationID, Memory>,
+    usages: BTreeMap<UsageKey, Stored<AppUsage>, Memory>,
+    deploy_credits: BTreeMap<DeveloperID, Stored<DeployCredit>, Memory>,
+    // Commissions that could not be transferred, by developer and the memo they were charged with.
+    unpaid_commissions: BTreeMap<(DeveloperID, u64), Stored<UnpaidCommission>, Memory>,
+    // Usage charges that could not be transferred, by developer and the memo they were charged with.
+    unpaid_usages: BTreeMap<(DeveloperID, u64), Stored<UnpaidUsage>, Memory>,
 }
 
 impl State {
     pub fn init_settings(&mut self, settings: Settings) {
-        self.settings.get_or_init(|| settings);
+        self.settings
+            .set(Some(Stored::new(&settings)))
+            .expect("Failed to write settings to stable memory");
+        self.cached_settings = Some(settings);
+    }
+
+    pub fn has_settings(&self) -> bool {
+        self.cached_settings.is_some()
     }
 
     pub fn settings(&self) -> &Settings {
-        self.settings
-            .get()
+        self.cached_settings
+            .as_ref()
             .expect("Canister is not initialized correctly")
     }
 
+    pub fn update_settings(&mut self, update: SettingsUpdate) -> Result<()> {
+        let mut settings = self.settings().clone();
+        settings.apply(update);
+        settings.validate()?;
+        self.init_settings(settings);
+        Ok(())
+    }
+
+    pub fn is_admin(&self, principal: &Principal) -> bool {
+        self.admins.contains_key(principal)
+    }
+
+    pub fn add_admin(&mut self, principal: Principal) {
+        self.admins.insert(principal, ());
+    }
+
+    pub fn remove_admin(&mut self, principal: &Principal) {
+        self.admins.remove(principal);
+    }
+
     pub fn register_developer(
         &mut self,
         developer_id: DeveloperID,
         developer: Developer,
     ) -> Result<()> {
-        self.developers.insert(developer_id, developer);
+        self.developers
+            .insert(developer_id, Stored::new(&developer));
+        Ok(())
+    }
+
+    pub fn update_developer(
+        &mut self,
+        developer_id: DeveloperID,
+        update: impl FnOnce(&mut Developer),
+    ) -> Result<()> {
+        let mut developer = self.get_developer(&developer_id)?;
+        update(&mut developer);
+        self.developers
+            .insert(developer_id, Stored::new(&developer));
         Ok(())
     }
 
     pub fn get_developer(&self, developer_id: &DeveloperID) -> Result<Developer> {
         self.developers
             .get(developer_id)
-            .ok_or(Error::DeveloperAccountNotFound)
+            .ok_or(Error::DeveloperAccountNotFound)?
+            .get()
     }
 
     pub fn get_app_of_developer(
@@ -79,68 +270,741 @@ impl State {
         developer_id: &DeveloperID,
         app_id: &AppID,
     ) -> Result<Option<App>> {
-        let developer = self.get_developer(developer_id)?;
-        Ok(developer
-            .apps
-            .contains(app_id)
-            .then(|| self.apps.get(app_id))
-            .flatten())
+        if !self.developers.contains_key(developer_id) {
+            return Err(Error::DeveloperAccountNotFound);
+        }
+        if !self.developer_apps.contains_key(&(*developer_id, *app_id)) {
+            return Ok(None);
+        }
+        self.apps.get(app_id).map(|app| app.get()).transpose()
     }
 
-    pub fn get_apps_of_developer(&self, developer_id: &DeveloperID) -> Result<Vec<(AppID, App)>> {
-        let developer = self.get_developer(developer_id)?;
-        Ok(developer
-            .apps
-            .into_iter()
-            .filter_map(|app_id| self.apps.get(&app_id).map(|a| (app_id, a)))
-            .collect())
+    /// Apps of `developer_id` by their ID, from `start` and up to `limit` of them, along with the
+    /// ID of the next app if any.
+    pub fn get_apps_of_developer(
+        &self,
+        developer_id: &DeveloperID,
+        start: Option<AppID>,
+        limit: usize,
+    ) -> Result<(AppsOfDeveloper, Option<AppID>)> {
+        if !self.developers.contains_key(developer_id) {
+            return Err(Error::DeveloperAccountNotFound);
+        }
+
+        let mut apps = Vec::new();
+        // The management canister ID is the smallest principal.
+        let start = start.unwrap_or_else(Principal::management_canister);
+        for ((_, app_id), _) in self
+            .developer_apps
+            .range((*developer_id, start)..)
+            .take_while(|((d, _), _)| d == developer_id)
+        {
+            if apps.len() == limit {
+                return Ok((apps, Some(app_id)));
+            }
+            if let Some(app) = self.apps.get(&app_id) {
+                apps.push((app_id, app.get()?));
+            }
+        }
+        Ok((apps, None))
+    }
+
+    /// Apps of `developer_id` that are not removed, apps that can not be read are counted.
+    pub fn apps_count(&self, developer_id: &DeveloperID) -> usize {
+        self.developer_apps
+            .range((*developer_id, Principal::management_canister())..)
+            .take_while(|((d, _), _)| d == developer_id)
+            .filter(|((_, app_id), _)| {
+                self.apps.get(app_id).is_some_and(|app| {
+                    app.get()
+                        .map_or(true, |app| matches!(app.state, AppState::Active(_)))
+                })
+            })
+            .count()
     }
 
-    pub fn register_app(&mut self, app_id: AppID, app: App) {
+    pub fn register_app(&mut self, app_id: AppID, app: App) -> Result<()> {
         let developer_id = app.developer_id;
-        let mut developer = self
-            .developers
-            .get(&developer_id)
-            .expect("Developer not found");
+        if !self.developers.contains_key(&developer_id) {
+            return Err(Error::DeveloperAccountNotFound);
+        }
+        self.developer_apps.insert((developer_id, app_id), ());
+        self.insert_app(app_id, &app);
+        Ok(())
+    }
 
-        self.apps.insert(app_id, app);
+    /// Mark the app as deleted, dropping its code. The app and its usages are kept for the records
+    /// of its developer.
+    pub fn remove_app(&mut self, app_id: AppID) -> Result<()> {
+        self.update_app(app_id, |app| app.state = AppState::Deleted)
+    }
 
-        developer.apps.push(app_id);
-        self.developers.insert(developer_id, developer);
+    pub fn get_app(&self, app_id: &AppID) -> Result<App> {
+        self.apps.get(app_id).ok_or(Error::AppNotFound)?.get()
     }
 
-    pub fn remove_app(&mut self, app_id: AppID) -> Result<()> {
-        if let Some(app) = self.apps.remove(&app_id) {
-            let mut developer = self
+    fn insert_app(&mut self, app_id: AppID, app: &App) {
+        // The usages still kept in the record by a previous version would be lost with it.
+        self.migrate_usages_of_app(app_id);
+        self.apps.insert(app_id, Stored::new(app));
+    }
+
+    pub fn upgrade_app(
+        &mut self,
+        app_id: AppID,
+        name: String,
+        package: AppPackage,
+        deployed_at: Timestamp,
+    ) -> Result<u32> {
+        let mut app = self.get_app(&app_id)?;
+        let AppState::Active(ref mut active_app) = app.state else {
+            return Err(Error::AppNotFound);
+        };
+        let revision = active_app.upgrade(name, package, deployed_at);
+        self.insert_app(app_id, &app);
+        Ok(revision)
+    }
+
+    pub fn update_app(&mut self, app_id: AppID, update: impl FnOnce(&mut App)) -> Result<()> {
+        let mut app = self.get_app(&app_id)?;
+        update(&mut app);
+        self.insert_app(app_id, &app);
+        Ok(())
+    }
+
+    /// Check that `app_id` can spend `amount` more within its budget, if any. The developer is
+    /// notified the first time in a period it can not.
+    pub fn check_app_budget(&mut self, app_id: AppID, amount: Tokens, now: u64) -> Result<()> {
+        let mut app = self.get_app(&app_id)?;
+        let Some(budget) = app.budget.clone() else {
+            return Ok(());
+        };
+        let Err(e) = app.budget_spending.check(&budget, amount, now) else {
+            return Ok(());
+        };
+
+        if app.budget_spending.should_notify(&budget, now) {
+            let spent = app.budget_spending.spent(&budget, now);
+            self.add_notification(
+                app.developer_id,
+                NotificationKind::AppBudgetExceeded {
+                    app_id,
+                    budget,
+                    spent,
+                },
+                now,
+            );
+            self.insert_app(app_id, &app);
+        }
+        Err(e)
+    }
+
+    pub fn record_app_spending(&mut self, app_id: AppID, amount: Tokens, now: u64) {
+        if let Ok(mut app) = self.get_app(&app_id) {
+            if let Some(budget) = app.budget.clone() {
+                app.budget_spending.record(&budget, amount, now);
+                self.insert_app(app_id, &app);
+            }
+        }
+    }
+
+    pub fn set_app_budget(&mut self, app_id: AppID, budget: Option<AppBudget>) -> Result<()> {
+        self.update_app(app_id, |app| app.budget = budget)
+    }
+
+    pub fn register_usage(&mut self, app_id: AppID, usage: AppUsage) -> Result<UsagePosition> {
+        if !self.apps.contains_key(&app_id) {
+            return Err(Error::AppNotFound);
+        }
+        Ok(self.insert_usage(app_id, usage))
+    }
+
+    fn insert_usage(&mut self, app_id: AppID, usage: AppUsage) -> UsagePosition {
+        let timestamp = usage.timestamp.timestamp_nanos;
+        let seq = self
+            .usages
+            .range((app_id, timestamp, 0)..=(app_id, timestamp, u64::MAX))
+            .count() as u64;
+        self.usages
+            .insert((app_id, timestamp, seq), Stored::new(&usage));
+        (timestamp, seq)
+    }
+
+    /// Mark a usage recorded as unpaid as paid, adding it to the spending of its app.
+    pub fn mark_usage_paid(&mut self, app_id: AppID, position: UsagePosition, now: u64) {
+        let key = (app_id, position.0, position.1);
+        let Some(Ok(mut usage)) = self.usages.get(&key).map(|usage| usage.get()) else {
+            return;
+        };
+        usage.is_paid = true;
+        self.usages.insert(key, Stored::new(&usage));
+        self.record_app_spending(app_id, usage.amount + usage.commission, now);
+    }
+
+    /// Usages of `app_id` from `start` and before the `end` timestamp, up to `limit` of them, along
+    /// with the start of the next ones if any. Fails while the usages of the app are still kept
+    /// in its record, until the migration moves them.
+    pub fn get_app_usages(
+        &self,
+        app_id: AppID,
+        start: UsagePosition,
+        end: u64,
+        limit: usize,
+    ) -> Result<(Vec<AppUsage>, Option<UsagePosition>)> {
+        let is_migrating = self
+            .apps
+            .get(&app_id)
+            .is_some_and(|app| app.version().is_ok_and(|v| v < App::VERSION));
+        if is_migrating {
+            return Err(Error::AppUsagesMigrating);
+        }
+
+        let mut usages = Vec::new();
+        if start.0 >= end {
+            return Ok((usages, None));
+        }
+
+        let range = (app_id, start.0, start.1)..=(app_id, end - 1, u64::MAX);
+        for ((_, timestamp, seq), usage) in self.usages.range(range) {
+            if usages.len() == limit {
+                return Ok((usages, Some((timestamp, seq))));
+            }
+            usages.push(usage.get()?);
+        }
+        Ok((usages, None))
+    }
+
+    /// Move the apps kept in the developer records by previous versions of the canister to their
+    /// own index. Developers that can not be read are left as they are.
+    pub fn migrate_developer_apps(&mut self) {
+        let legacy_developers: Vec<_> = self
+            .developers
+            .iter()
+            .filter(|(_, developer)| developer.version().is_ok_and(|v| v < Developer::VERSION))
+            .map(|(developer_id, _)| developer_id)
+            .collect();
+
+        for developer_id in legacy_developers {
+            let Some(Ok(Some(developer))) = self
                 .developers
-                .get(&app.developer_id)
-                .expect("Developer not found");
+                .get(&developer_id)
+                .map(|d| v1::Developer::from_stored(&d))
+            else {
+                continue;
+            };
+            for app_id in &developer.apps {
+                self.developer_apps.insert((developer_id, *app_id), ());
+            }
+            self.developers
+                .insert(developer_id, Stored::new(&developer.into()));
+        }
+    }
+
+    /// Move the usages kept in the app records by previous versions of the canister to their own
+    /// map, for the apps from `start` until `should_pause` is true. Returns the app to continue
+    /// from, if any is left. Apps that can not be read are left as they are.
+    pub fn migrate_app_usages(
+        &mut self,
+        start: Option<AppID>,
+        should_pause: impl Fn() -> bool,
+    ) -> Option<AppID> {
+        let mut start = Bound::Included(start.unwrap_or_else(Principal::management_canister));
+        loop {
+            let app_id = self
+                .apps
+                .range((start, Bound::Unbounded))
+                .find(|(_, app)| app.version().is_ok_and(|v| v < App::VERSION))
+                .map(|(app_id, _)| app_id)?;
+            if should_pause() {
+                return Some(app_id);
+            }
+            self.migrate_usages_of_app(app_id);
+            start = Bound::Excluded(app_id);
+        }
+    }
+
+    fn migrate_usages_of_app(&mut self, app_id: AppID) {
+        let Some(Ok(Some(mut app))) = self.apps.get(&app_id).map(|a| v1::App::from_stored(&a))
+        else {
+            return;
+        };
+        let usages = app.take_usages();
+        self.apps.insert(app_id, Stored::new(&app.into()));
+        for usage in usages {
+            self.insert_usage(app_id, usage);
+        }
+    }
+
+    pub fn begin_upload(&mut self, upload: Upload) -> UploadID {
+        let upload_id = *self.next_upload_id.get();
+        self.next_upload_id
+            .set(upload_id + 1)
+            .expect("Failed to write upload id to stable memory");
+        self.developer_uploads.insert(
+            (upload.developer_id, upload_id),
+            upload.created_at.timestamp_nanos,
+        );
+        self.uploads.insert(upload_id, Stored::new(&upload));
+        upload_id
+    }
+
+    /// Uploads of the developer that have not expired yet, finalized or not.
+    pub fn uploads_count_of(&self, developer_id: &DeveloperID, now: u64) -> u64 {
+        self.developer_uploads
+            .range((*developer_id, 0)..=(*developer_id, UploadID::MAX))
+            .filter(|(_, created_at)| !is_upload_expired(*created_at, now))
+            .count() as u64
+    }
+
+    pub fn get_upload_of_developer(
+        &self,
+        developer_id: &DeveloperID,
+        upload_id: UploadID,
+    ) -> Result<Upload> {
+        Some(
+            self.uploads
+                .get(&upload_id)
+                .ok_or(Error::UploadNotFound)?
+                .get()?,
+        )
+        .filter(|u| u.developer_id == *developer_id && !u.is_expired(ic_cdk::api::time()))
+        .ok_or(Error::UploadNotFound)
+    }
 
-            developer.apps.retain(|a| *a != app_id);
-            self.developers.insert(app.developer_id, developer);
+    pub fn append_upload_chunk(
+        &mut self,
+        developer_id: &DeveloperID,
+        upload_id: UploadID,
+        chunk: Vec<u8>,
+    ) -> Result<()> {
+        let mut upload = self.get_upload_of_developer(developer_id, upload_id)?;
+        if upload.finalized {
+            return Err(Error::UploadIsFinalized);
+        }
+        if upload.received + chunk.len() as u64 > upload.size {
+            return Err(Error::UploadSizeLimitExceeded { max: upload.size });
         }
+
+        upload.received += chunk.len() as u64;
+        self.upload_chunks
+            .insert((upload_id, upload.chunks_count), chunk);
+        upload.chunks_count += 1;
+        self.uploads.insert(upload_id, Stored::new(&upload));
         Ok(())
     }
 
-    pub fn get_app(&self, app_id: &AppID) -> Result<App> {
-        self.apps.get(app_id).ok_or(Error::AppNotFound)
+    pub fn finalize_upload(
+        &mut self,
+        developer_id: &DeveloperID,
+        upload_id: UploadID,
+        sha256: &[u8],
+    ) -> Result<()> {
+        let mut upload = self.get_upload_of_developer(developer_id, upload_id)?;
+        if upload.finalized {
+            return Err(Error::UploadIsFinalized);
+        }
+        if upload.received != upload.size {
+            return Err(Error::UploadIncomplete {
+                received: upload.received,
+                expected: upload.size,
+            });
+        }
+
+        let mut hasher = Sha256::new();
+        for (_, chunk) in self
+            .upload_chunks
+            .range((upload_id, 0)..=(upload_id, u32::MAX))
+        {
+            hasher.update(chunk);
+        }
+        if hasher.finalize().as_slice() != sha256 {
+            return Err(Error::UploadHashMismatch);
+        }
+
+        upload.finalized = true;
+        self.uploads.insert(upload_id, Stored::new(&upload));
+        Ok(())
+    }
+
+    /// Concatenated data of a finalized upload.
+    pub fn get_upload_data(
+        &self,
+        developer_id: &DeveloperID,
+        upload_id: UploadID,
+    ) -> Result<Vec<u8>> {
+        let upload = self.get_upload_of_developer(developer_id, upload_id)?;
+        if !upload.finalized {
+            return Err(Error::UploadIsNotFinalized);
+        }
+
+        let mut data = Vec::with_capacity(upload.size as usize);
+        for (_, chunk) in self
+            .upload_chunks
+            .range((upload_id, 0)..=(upload_id, u32::MAX))
+        {
+            data.extend_from_slice(&chunk);
+        }
+        Ok(data)
+    }
+
+    pub fn remove_upload(&mut self, upload_id: UploadID) {
+        if let Some(Ok(upload)) = self.uploads.remove(&upload_id).map(|u| u.get()) {
+            self.developer_uploads
+                .remove(&(upload.developer_id, upload_id));
+        }
+        let chunks: Vec<_> = self
+            .upload_chunks
+            .range((upload_id, 0)..=(upload_id, u32::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in chunks {
+            self.upload_chunks.remove(&key);
+        }
+    }
+
+    /// Remove the uploads that expired, oldest first, stopping at the first one that has not.
+    /// Uploads that can not be read are removed too, they can not be deployed.
+    pub fn remove_expired_uploads(&mut self, now: u64) {
+        while let Some((upload_id, upload)) = self.uploads.first_key_value() {
+            if upload.get().is_ok_and(|upload| !upload.is_expired(now)) {
+                break;
+            }
+            self.remove_upload(upload_id);
+        }
+    }
+
+    pub fn next_operation_id(&mut self) -> u64 {
+        let operation_id = *self.next_operation_id.get();
+        self.next_operation_id
+            .set(operation_id + 1)
+            .expect("Failed to write operation id to stable memory");
+        operation_id
+    }
+
+    pub fn get_journal_entry(&self, key: &JournalKey) -> Result<Option<JournalEntry>> {
+        self.journal.get(key).map(|entry| entry.get()).transpose()
+    }
+
+    pub fn begin_journal_entry(
+        &mut self,
+        key: JournalKey,
+        operation: Operation,
+        args_hash: [u8; 32],
+    ) -> JournalEntry {
+        let expired_before = operation
+            .created_at_time
+            .saturating_sub(JOURNAL_RETENTION_NANOS);
+        while let Some(((created_at_time, expired_key), _)) = self.journal_expiry.first_key_value()
+        {
+            if created_at_time >= expired_before {
+                break;
+            }
+            self.journal_expiry.remove(&(created_at_time, expired_key));
+            self.journal.remove(&expired_key);
+        }
+
+        // An entry begun again expires with its new operation.
+        if let Some(Ok(previous)) = self.journal.get(&key).map(|entry| entry.get()) {
+            self.journal_expiry
+                .remove(&(previous.operation.created_at_time, key));
+        }
+        let entry = JournalEntry {
+            operation,
+            args_hash,
+            block_index: None,
+            completed: false,
+        };
+        self.journal.insert(key, Stored::new(&entry));
+        self.journal_expiry
+            .insert((operation.created_at_time, key), ());
+        entry
+    }
+
+    pub fn complete_journal_entry(&mut self, key: &JournalKey, block_index: Option<BlockIndex>) {
+        if let Some(Ok(mut entry)) = self.journal.get(key).map(|entry| entry.get()) {
+            entry.block_index = block_index;
+            entry.completed = true;
+            self.journal.insert(*key, Stored::new(&entry));
+        }
+    }
+
+    // A rate that can not be read is renewed, as if there was none.
+    pub fn icp_cycles_rate(&self) -> Option<IcpCyclesRate> {
+        self.icp_cycles_rate
+            .get()
+            .as_ref()
+            .and_then(|rate| rate.get().ok())
+    }
+
+    pub fn set_icp_cycles_rate(&mut self, rate: IcpCyclesRate) {
+        self.icp_cycles_rate
+            .set(Some(Stored::new(&rate)))
+            .expect("Failed to write exchange rate to stable memory");
+    }
+
+    pub fn add_notification(
+        &mut self,
+        developer_id: DeveloperID,
+        kind: NotificationKind,
+        now: u64,
+    ) {
+        let ids: Vec<NotificationID> = self
+            .notifications
+            .range((developer_id, 0)..=(developer_id, NotificationID::MAX))
+            .map(|((_, id), _)| id)
+            .collect();
+        let id = *self.next_notification_id.get();
+        self.next_notification_id
+            .set(id + 1)
+            .expect("Failed to write notification id to stable memory");
+        for old_id in ids
+            .iter()
+            .take((ids.len() + 1).saturating_sub(MAX_NOTIFICATIONS_PER_DEVELOPER))
+        {
+            self.notifications.remove(&(developer_id, *old_id));
+        }
+
+        let notification = Notification {
+            id,
+            created_at: Timestamp {
+                timestamp_nanos: now,
+            },
+            kind,
+        };
+        self.notifications
+            .insert((developer_id, id), Stored::new(&notification));
+    }
+
+    pub fn get_notifications(&self, developer_id: &DeveloperID) -> Result<Vec<Notification>> {
+        self.notifications
+            .range((*developer_id, 0)..=(*developer_id, NotificationID::MAX))
+            .map(|(_, notification)| notification.get())
+            .collect()
+    }
+
+    pub fn clear_notifications(&mut self, developer_id: &DeveloperID, up_to: NotificationID) {
+        let ids: Vec<_> = self
+            .notifications
+            .range((*developer_id, 0)..=(*developer_id, up_to))
+            .map(|(key, _)| key)
+            .collect();
+        for key in ids {
+            self.notifications.remove(&key);
+        }
+    }
+
+    pub fn insert_pending_top_up(&mut self, top_up: PendingTopUp) {
+        self.pending_top_ups
+            .insert(top_up.block_index, Stored::new(&top_up));
+    }
+
+    // Top-ups that can not be read are left as they are.
+    pub fn get_pending_top_up(&self, block_index: BlockIndex) -> Option<PendingTopUp> {
+        self.pending_top_ups
+            .get(&block_index)
+            .and_then(|top_up| top_up.get().ok())
+    }
+
+    pub fn update_pending_top_up(
+        &mut self,
+        block_index: BlockIndex,
+        update: impl FnOnce(&mut PendingTopUp),
+    ) {
+        if let Some(mut top_up) = self.get_pending_top_up(block_index) {
+            update(&mut top_up);
+            self.pending_top_ups
+                .insert(block_index, Stored::new(&top_up));
+        }
     }
 
-    pub fn register_usage(&mut self, app_id: AppID, usage: AppUsage) -> Result<()> {
-        let mut app = self.apps.get(&app_id).ok_or(Error::AppNotFound)?;
-        app.usages.push(usage);
-        self.apps.insert(app_id, app);
+    pub fn remove_pending_top_up(&mut self, block_index: BlockIndex) {
+        self.pending_top_ups.remove(&block_index);
+    }
+
+    // Failed top-ups are kept for the records of their developer, they are not retried.
+    pub fn get_pending_top_ups_to_retry(&self) -> Vec<BlockIndex> {
+        self.pending_top_ups
+            .iter()
+            .filter(|(_, top_up)| {
+                top_up
+                    .get()
+                    .is_ok_and(|top_up| top_up.status == TopUpStatus::Pending)
+            })
+            .map(|(block_index, _)| block_index)
+            .collect()
+    }
+
+    pub fn get_pending_top_ups_of_developer(
+        &self,
+        developer_id: &DeveloperID,
+    ) -> Vec<PendingTopUp> {
+        self.pending_top_ups
+            .iter()
+            .filter_map(|(_, top_up)| top_up.get().ok())
+            .filter(|t| t.developer_id == *developer_id)
+            .collect()
+    }
+
+    pub fn get_deploy_credit(&self, developer_id: &DeveloperID) -> Result<Option<DeployCredit>> {
+        self.deploy_credits
+            .get(developer_id)
+            .map(|credit| credit.get())
+            .transpose()
+    }
+
+    pub fn add_deploy_credit(
+        &mut self,
+        developer_id: DeveloperID,
+        cycles: u128,
+        amount: Tokens,
+    ) -> Result<()> {
+        let mut credit = self.get_deploy_credit(&developer_id)?.unwrap_or_default();
+        credit.cycles += cycles;
+        credit.amount += amount;
+        self.deploy_credits
+            .insert(developer_id, Stored::new(&credit));
         Ok(())
     }
+
+    pub fn insert_unpaid_commission(
+        &mut self,
+        developer_id: DeveloperID,
+        commission: UnpaidCommission,
+    ) {
+        self.unpaid_commissions.insert(
+            (developer_id, commission.operation.memo),
+            Stored::new(&commission),
+        );
+    }
+
+    pub fn update_unpaid_commission(
+        &mut self,
+        developer_id: DeveloperID,
+        memo: u64,
+        update: impl FnOnce(&mut UnpaidCommission),
+    ) {
+        let key = (developer_id, memo);
+        if let Some(Ok(mut commission)) = self.unpaid_commissions.get(&key).map(|c| c.get()) {
+            update(&mut commission);
+            self.unpaid_commissions
+                .insert(key, Stored::new(&commission));
+        }
+    }
+
+    pub fn remove_unpaid_commission(&mut self, developer_id: DeveloperID, memo: u64) {
+        self.unpaid_commissions.remove(&(developer_id, memo));
+    }
+
+    // Commissions that can not be read are left as they are.
+    pub fn get_unpaid_commissions(&self) -> Vec<(DeveloperID, u64, UnpaidCommission)> {
+        self.unpaid_commissions
+            .iter()
+            .filter_map(|((developer_id, memo), commission)| {
+                Some((developer_id, memo, commission.get().ok()?))
+            })
+            .collect()
+    }
+
+    /// Total commission the developer still owes.
+    pub fn unpaid_commission_of(&self, developer_id: &DeveloperID) -> Result<Tokens> {
+        let mut e8s = 0;
+        for (_, commission) in self
+            .unpaid_commissions
+            .range((*developer_id, 0)..=(*developer_id, u64::MAX))
+        {
+            e8s += commission.get()?.amount.e8s();
+        }
+        Ok(Tokens::from_e8s(e8s))
+    }
+
+    pub fn insert_unpaid_usage(&mut self, developer_id: DeveloperID, usage: UnpaidUsage) {
+        self.unpaid_usages
+            .insert((developer_id, usage.operation.memo), Stored::new(&usage));
+    }
+
+    // Charges that can not be read are left as they are.
+    pub fn get_unpaid_usage(&self, developer_id: DeveloperID, memo: u64) -> Option<UnpaidUsage> {
+        self.unpaid_usages
+            .get(&(developer_id, memo))
+            .and_then(|usage| usage.get().ok())
+    }
+
+    pub fn update_unpaid_usage(
+        &mut self,
+        developer_id: DeveloperID,
+        memo: u64,
+        update: impl FnOnce(&mut UnpaidUsage),
+    ) {
+        if let Some(mut usage) = self.get_unpaid_usage(developer_id, memo) {
+            update(&mut usage);
+            self.unpaid_usages
+                .insert((developer_id, memo), Stored::new(&usage));
+        }
+    }
+
+    pub fn remove_unpaid_usage(&mut self, developer_id: DeveloperID, memo: u64) {
+        self.unpaid_usages.remove(&(developer_id, memo));
+    }
+
+    pub fn get_unpaid_usages(&self) -> Vec<(DeveloperID, u64, UnpaidUsage)> {
+        self.unpaid_usages
+            .iter()
+            .filter_map(|((developer_id, memo), usage)| {
+                Some((developer_id, memo, usage.get().ok()?))
+            })
+            .collect()
+    }
+
+    /// Take `spent` off the credit of the developer, which may have grown since it was read.
+    pub fn spend_deploy_credit(&mut self, developer_id: DeveloperID, spent: &DeployCredit) {
+        let Ok(Some(mut credit)) = self.get_deploy_credit(&developer_id) else {
+            return;
+        };
+        credit.cycles = credit.cycles.saturating_sub(spent.cycles);
+        credit.amount = Tokens::from_e8s(credit.amount.e8s().saturating_sub(spent.amount.e8s()));
+        if credit.cycles == 0 {
+            self.deploy_credits.remove(&developer_id);
+        } else {
+            self.deploy_credits
+                .insert(developer_id, Stored::new(&credit));
+        }
+    }
 }
 
 impl Default for State {
     fn default() -> Self {
+        let settings: StableCell<Option<Stored<Settings>>, Memory> =
+            StableCell::init(get_settings_cell_memory(), None)
+                .expect("Failed to initialize settings stable cell");
+        // Settings that can not be read are left for the upgrade to set again.
+        let cached_settings = settings.get().as_ref().and_then(|s| s.get().ok());
+
         Self {
-            settings: OnceLock::new(),
+            settings,
+            cached_settings,
+            admins: BTreeMap::init(get_admins_btree_memory()),
             developers: BTreeMap::init(get_users_btree_memory()),
             apps: BTreeMap::init(get_apps_btree_memory()),
-            icp_cycles_exchange_rate: None,
+            developer_apps: BTreeMap::init(get_developer_apps_btree_memory()),
+            uploads: BTreeMap::init(get_uploads_btree_memory()),
+            upload_chunks: BTreeMap::init(get_upload_chunks_btree_memory()),
+            developer_uploads: BTreeMap::init(get_developer_uploads_btree_memory()),
+            next_upload_id: StableCell::init(get_upload_id_cell_memory(), 0)
+                .expect("Failed to initialize upload id stable cell"),
+            next_operation_id: StableCell::init(get_operation_id_cell_memory(), 0)
+                .expect("Failed to initialize operation id stable cell"),
+            journal: BTreeMap::init(get_journal_btree_memory()),
+            journal_expiry: BTreeMap::init(get_journal_expiry_btree_memory()),
+            pending_top_ups: BTreeMap::init(get_pending_top_ups_btree_memory()),
+            icp_cycles_rate: StableCell::init(get_icp_cycles_rate_cell_memory(), None)
+                .expect("Failed to initialize exchange rate stable cell"),
+            notifications: BTreeMap::init(get_notifications_btree_memory()),
+            next_notification_id: StableCell::init(get_notification_id_cell_memory(), 0)
+                .expect("Failed to initialize notification id stable cell"),
+            usages: BTreeMap::init(get_usages_btree_memory()),
+            deploy_credits: BTreeMap::init(get_deploy_credits_btree_memory()),
+            unpaid_commissions: BTreeMap::init(get_unpaid_commissions_btree_memory()),
+            unpaid_usages: BTreeMap::init(get_unpaid_usages_btree_memory()),
         }
     }
 }
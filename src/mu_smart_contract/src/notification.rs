@@ -0,0 +1,52 @@
+use candid::CandidType;
+use candid::Deserialize;
+use ic_ledger_types::Timestamp;
+use ic_ledger_types::Tokens;
+
+use crate::app::budget::AppBudget;
+use crate::app::AppID;
+use crate::developer::Developer;
+use crate::memory::STATE;
+use crate::schema::Versioned;
+use crate::Result;
+
+pub type NotificationID = u64;
+
+// The oldest notifications of a developer are dropped past this count.
+pub const MAX_NOTIFICATIONS_PER_DEVELOPER: usize = 100;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub enum NotificationKind {
+    /// A cycles request or a usage charge of the app was refused because of its budget.
+    AppBudgetExceeded {
+        app_id: AppID,
+        budget: AppBudget,
+        spent: Tokens,
+    },
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Notification {
+    pub id: NotificationID,
+    pub created_at: Timestamp,
+    pub kind: NotificationKind,
+}
+
+impl Versioned for Notification {
+    const NAME: &'static str = "notification";
+    const VERSION: u8 = 1;
+}
+
+#[ic_cdk::query]
+fn get_notifications() -> Result<Vec<crate::notification::Notification>> {
+    let (developer_id, _) = Developer::get_caller_developer_account()?;
+    STATE.with_borrow(|s| s.get_notifications(&developer_id))
+}
+
+/// Remove the notifications of the caller up to `up_to`, included.
+#[ic_cdk::update]
+fn clear_notifications(up_to: crate::notification::NotificationID) -> Result<()> {
+    let (developer_id, _) = Developer::get_caller_developer_account()?;
+    STATE.with_borrow_mut(|s| s.clear_notifications(&developer_id, up_to));
+    Ok(())
+}
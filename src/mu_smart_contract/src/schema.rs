@@ -0,0 +1,166 @@
+use std::borrow::Cow;
+use std::marker::PhantomData;
+
+use candid::CandidType;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::Storable;
+use serde::de::DeserializeOwned;
+
+use crate::error::Error;
+use crate::Result;
+
+pub mod v1;
+
+// Records stored before they were versioned are plain Candid messages, starting with this magic.
+const CANDID_MAGIC: &[u8] = b"DIDL";
+
+/// A record stored with the version of its schema, so it can be migrated when the schema changes.
+///
+/// Changing the shape of a record means bumping `VERSION`, keeping the previous shape in its own
+/// module, and decoding it in `migrate`.
+pub trait Versioned: CandidType + DeserializeOwned {
+    const NAME: &'static str;
+    const VERSION: u8;
+
+    /// Decode `payload`, stored with an older `version` of the schema. Records that never changed
+    /// shape have no older versions.
+    fn migrate(version: u8, _payload: &[u8]) -> Result<Self> {
+        Err(Error::Internal(format!(
+            "Unknown {} record version {version}",
+            Self::NAME
+        )))
+    }
+}
+
+/// Decode a Candid `payload` of a record.
+pub fn decode<T: Versioned, S: DeserializeOwned + CandidType>(payload: &[u8]) -> Result<S> {
+    candid::decode_one(payload)
+        .map_err(|e| Error::Internal(format!("Failed to decode {}, reason: {e}", T::NAME)))
+}
+
+/// Stored bytes of a versioned record. The record is decoded when read, so a record that can not
+/// be decoded is an error instead of a trap.
+pub struct Stored<T> {
+    bytes: Vec<u8>,
+    _record: PhantomData<T>,
+}
+
+impl<T: Versioned> Stored<T> {
+    pub fn new(record: &T) -> Self {
+        let mut bytes = vec![T::VERSION];
+        bytes.extend(candid::encode_one(record).expect("Failed to encode record"));
+        Self {
+            bytes,
+            _record: PhantomData,
+        }
+    }
+
+    pub fn get(&self) -> Result<T> {
+        let (version, payload) = self.split()?;
+        match version {
+            v if v == T::VERSION => decode::<T, T>(payload),
+            v if v < T::VERSION => T::migrate(version, payload),
+            _ => Err(Error::Internal(format!(
+                "Unknown {} record version {version}",
+                T::NAME
+            ))),
+        }
+    }
+
+    /// Version of the schema the record was stored with.
+    pub fn version(&self) -> Result<u8> {
+        self.split().map(|(version, _)| version)
+    }
+
+    /// Version and Candid payload of the record.
+    pub fn split(&self) -> Result<(u8, &[u8])> {
+        if self.bytes.starts_with(CANDID_MAGIC) {
+            return Ok((1, self.bytes.as_slice()));
+        }
+        match self.bytes.split_first() {
+            Some((version, payload)) => Ok((*version, payload)),
+            None => Err(Error::Internal(format!("Empty {} record", T::NAME))),
+        }
+    }
+}
+
+impl<T> Storable for Stored<T> {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Borrowed(&self.bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self {
+            bytes: bytes.into_owned(),
+            _record: PhantomData,
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::Principal;
+    use ic_ledger_types::Subaccount;
+
+    use super::*;
+    use crate::developer::Developer;
+
+    #[derive(CandidType)]
+    struct LegacyDeveloper {
+        escrow_account: Subaccount,
+        apps: Vec<Principal>,
+    }
+
+    fn stored(bytes: Vec<u8>) -> Stored<Developer> {
+        Stored::from_bytes(Cow::Owned(bytes))
+    }
+
+    #[test]
+    fn reads_records_stored_before_versioning() {
+        let app_id = Principal::anonymous();
+        let bytes = candid::encode_one(LegacyDeveloper {
+            escrow_account: Subaccount([1; 32]),
+            apps: vec![app_id],
+        })
+        .unwrap();
+
+        let stored = stored(bytes);
+        let developer = stored.get().unwrap();
+        assert_eq!(developer.escrow_account, Subaccount([1; 32]));
+        assert!(developer.standing_allowance.is_none());
+
+        // The apps are kept for the upgrade to move them to their own index.
+        let legacy = v1::Developer::from_stored(&stored).unwrap().unwrap();
+        assert_eq!(legacy.apps, vec![app_id]);
+    }
+
+    #[test]
+    fn reads_current_records() {
+        let bytes = candid::encode_one(LegacyDeveloper {
+            escrow_account: Subaccount([2; 32]),
+            apps: Vec::new(),
+        })
+        .unwrap();
+        let developer = stored(bytes).get().unwrap();
+
+        let stored = Stored::new(&developer);
+        assert_eq!(stored.to_bytes()[0], Developer::VERSION);
+        assert_eq!(stored.get().unwrap().escrow_account, Subaccount([2; 32]));
+        assert!(v1::Developer::from_stored(&stored).unwrap().is_none());
+    }
+
+    #[test]
+    fn fails_on_unreadable_records() {
+        assert!(matches!(stored(Vec::new()).get(), Err(Error::Internal(_))));
+        assert!(matches!(
+            stored(vec![Developer::VERSION + 1, 0]).get(),
+            Err(Error::Internal(_))
+        ));
+        assert!(matches!(
+            stored(vec![Developer::VERSION, 1, 2, 3]).get(),
+            Err(Error::Internal(_))
+        ));
+    }
+}
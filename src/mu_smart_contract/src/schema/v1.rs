@@ -0,0 +1,142 @@
+use candid::CandidType;
+use candid::Deserialize;
+use ic_ledger_types::Subaccount;
+use ic_ledger_types::Timestamp;
+use ic_ledger_types::Tokens;
+use serde_bytes::ByteBuf;
+
+use crate::app::AppID;
+use crate::developer::DeveloperID;
+use crate::schema::decode;
+use crate::schema::Stored;
+use crate::Result;
+
+// Records as stored by the first version of the canister, before they were versioned.
+
+#[derive(CandidType, Deserialize)]
+pub struct Developer {
+    escrow_account: Subaccount,
+    pub apps: Vec<AppID>,
+}
+
+impl Developer {
+    /// Decode a developer stored with the first version of the schema, `None` for the later ones.
+    pub fn from_stored(stored: &Stored<crate::developer::Developer>) -> Result<Option<Self>> {
+        let (version, payload) = stored.split()?;
+        Ok(match version {
+            1 => Some(decode::<crate::developer::Developer, Self>(payload)?),
+            _ => None,
+        })
+    }
+}
+
+// The apps are moved to the index on upgrade, before the developers are read.
+impl From<Developer> for crate::developer::Developer {
+    fn from(developer: Developer) -> Self {
+        Self {
+            escrow_account: developer.escrow_account,
+            standing_allowance: None,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+enum UsageKind {
+    CyclesCharge { cylces: u128 },
+    // Never recorded, it was a placeholder for the usage of additional services.
+    AdditionalServices { details: ByteBuf },
+}
+
+#[derive(CandidType, Deserialize)]
+struct AppUsage {
+    kind: UsageKind,
+    timestamp: Timestamp,
+    amount: Tokens,
+    is_paid: bool,
+}
+
+#[derive(CandidType, Deserialize)]
+enum AppState {
+    Active(ActiveApp),
+    Deleted,
+}
+
+#[derive(CandidType, Deserialize)]
+struct ActiveApp {
+    revision: u32,
+    name: String,
+    data: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct App {
+    developer_id: DeveloperID,
+    state: AppState,
+    usages: Vec<AppUsage>,
+}
+
+impl App {
+    /// Decode an app stored with the first version of the schema, `None` for the later ones.
+    pub fn from_stored(stored: &Stored<crate::app::App>) -> Result<Option<Self>> {
+        let (version, payload) = stored.split()?;
+        Ok(match version {
+            1 => Some(decode::<crate::app::App, Self>(payload)?),
+            _ => None,
+        })
+    }
+
+    /// Take the usages kept in the record, to move them to their own map.
+    pub fn take_usages(&mut self) -> Vec<crate::app::AppUsage> {
+        std::mem::take(&mut self.usages)
+            .into_iter()
+            .filter_map(|usage| match usage.kind {
+                UsageKind::CyclesCharge { cylces } => Some(crate::app::AppUsage {
+                    kind: crate::app::UsageKind::CyclesCharge { cylces },
+                    timestamp: usage.timestamp,
+                    amount: usage.amount,
+                    commission: Tokens::from_e8s(0),
+                    is_paid: usage.is_paid,
+                }),
+                UsageKind::AdditionalServices { .. } => None,
+            })
+            .collect()
+    }
+}
+
+// The usages are moved on upgrade, before the apps are read.
+impl From<App> for crate::app::App {
+    fn from(app: App) -> Self {
+        let state = match app.state {
+            AppState::Active(active_app) => {
+                // Apps were deployed without a manifest, the app name stands in for it.
+                crate::app::AppState::Active(crate::app::ActiveApp {
+                    revision: active_app.revision,
+                    manifest: crate::app::package::Manifest {
+                        name: active_app.name.clone(),
+                        version: String::new(),
+                        description: None,
+                    },
+                    name: active_app.name,
+                    data: active_app.data,
+                    deployed_at: Timestamp { timestamp_nanos: 0 },
+                    previous_revisions: Vec::new(),
+                })
+            }
+            AppState::Deleted => crate::app::AppState::Deleted,
+        };
+
+        Self {
+            developer_id: app.developer_id,
+            state,
+            // Apps could request any amount of cycles, until their developer sets a policy.
+            cycles_policy: crate::app::cycles_policy::CyclesPolicy {
+                max_cycles_per_request: None,
+                max_cycles_per_day: None,
+                cooldown_seconds: None,
+            },
+            cycles_requests: crate::app::cycles_policy::CyclesRequests::default(),
+            budget: None,
+            budget_spending: crate::app::budget::BudgetSpending::default(),
+        }
+    }
+}
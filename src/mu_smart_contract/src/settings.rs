@@ -1,33 +1,376 @@
+use crate::app::start_app_usages_migration;
+use crate::commission::start_settlement_timer;
+use crate::error::Error;
 use crate::memory::STATE;
+use crate::schema::Versioned;
+use crate::top_up::start_retry_timer;
+use crate::upload::start_upload_purge_timer;
+use crate::upload::MAX_UPLOAD_SIZE;
+use crate::usage::start_usage_settlement_timer;
+use crate::usage::ServicePrices;
+use crate::utils::exchange::start_exchange_rate_refresh_timer;
+use crate::utils::exchange::MAINNET_CYCLE_MINTER_CANISTER_ID;
+use crate::utils::exchange::MAINNET_EXCHANGE_RATE_CANISTER_ID;
+use crate::Result;
 use candid::CandidType;
 use candid::Deserialize;
+use candid::Principal;
+use ic_ledger_types::AccountIdentifier;
 use ic_ledger_types::Tokens;
+use ic_ledger_types::MAINNET_LEDGER_CANISTER_ID;
 use std::time::Duration;
 
-#[derive(Debug)]
+pub const DEFAULT_EXCHANGE_RATE_TOLERANCE: f32 = 0.05;
+// Commission rates are in basis points, hundredths of a percent.
+const BASIS_POINTS: u64 = 10_000;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
 pub struct Settings {
     pub minimum_escrow_balance_for_deploy: Tokens,
     pub max_apps_per_developer: usize,
-    pub commition_rate: f32,
+    pub commission_rate_bps: u32,
+    pub treasury_account: AccountIdentifier,
     pub exchange_rate_timeout: Duration,
+    // Maximum relative difference of the exchange rate canister rate from the cycles minting
+    // canister one.
+    pub exchange_rate_tolerance: f32,
+    pub max_wasm_module_size: u64,
+    pub cycles_request_threshold: u64,
+    pub cycles_request_amount: u64,
+    // Cycles policy of new apps, along with `cycles_request_amount` per request.
+    pub max_cycles_per_day: u64,
+    pub cycles_request_cooldown_seconds: u64,
+    pub manager_canisters: Vec<Principal>,
+    pub service_prices: ServicePrices,
+    pub ledger_canister_id: Principal,
+    pub cycles_minting_canister_id: Principal,
+    pub exchange_rate_canister_id: Principal,
+}
+
+impl Settings {
+    /// Override the settings that are set in `update`.
+    pub fn apply(&mut self, update: SettingsUpdate) {
+        let SettingsUpdate {
+            minimum_escrow_balance_for_deploy,
+            max_apps_per_developer,
+            commission_rate_bps,
+            treasury_account,
+            exchange_rate_timeout_seconds,
+            exchange_rate_tolerance,
+            max_wasm_module_size,
+            cycles_request_threshold,
+            cycles_request_amount,
+            max_cycles_per_day,
+            cycles_request_cooldown_seconds,
+            manager_canisters,
+            service_prices,
+            ledger_canister_id,
+            cycles_minting_canister_id,
+            exchange_rate_canister_id,
+        } = update;
+
+        if let Some(minimum_escrow_balance_for_deploy) = minimum_escrow_balance_for_deploy {
+            self.minimum_escrow_balance_for_deploy = minimum_escrow_balance_for_deploy;
+        }
+        if let Some(max_apps_per_developer) = max_apps_per_developer {
+            self.max_apps_per_developer = max_apps_per_developer;
+        }
+        if let Some(commission_rate_bps) = commission_rate_bps {
+            self.commission_rate_bps = commission_rate_bps;
+        }
+        if let Some(treasury_account) = treasury_account {
+            self.treasury_account = treasury_account;
+        }
+        if let Some(exchange_rate_timeout_seconds) = exchange_rate_timeout_seconds {
+            self.exchange_rate_timeout = Duration::from_secs(exchange_rate_timeout_seconds);
+        }
+        if let Some(exchange_rate_tolerance) = exchange_rate_tolerance {
+            self.exchange_rate_tolerance = exchange_rate_tolerance;
+        }
+        if let Some(max_wasm_module_size) = max_wasm_module_size {
+            self.max_wasm_module_size = max_wasm_module_size;
+        }
+        if let Some(cycles_request_threshold) = cycles_request_threshold {
+            self.cycles_request_threshold = cycles_request_threshold;
+        }
+        if let Some(cycles_request_amount) = cycles_request_amount {
+            self.cycles_request_amount = cycles_request_amount;
+        }
+        if let Some(max_cycles_per_day) = max_cycles_per_day {
+            self.max_cycles_per_day = max_cycles_per_day;
+        }
+        if let Some(cycles_request_cooldown_seconds) = cycles_request_cooldown_seconds {
+            self.cycles_request_cooldown_seconds = cycles_request_cooldown_seconds;
+        }
+        if let Some(manager_canisters) = manager_canisters {
+            self.manager_canisters = manager_canisters;
+        }
+        if let Some(service_prices) = service_prices {
+            self.service_prices = service_prices;
+        }
+        if let Some(ledger_canister_id) = ledger_canister_id {
+            self.ledger_canister_id = ledger_canister_id;
+        }
+        if let Some(cycles_minting_canister_id) = cycles_minting_canister_id {
+            self.cycles_minting_canister_id = cycles_minting_canister_id;
+        }
+        if let Some(exchange_rate_canister_id) = exchange_rate_canister_id {
+            self.exchange_rate_canister_id = exchange_rate_canister_id;
+        }
+    }
+
+    /// Platform commission on a charge of `amount`, rounded up to the next e8.
+    pub fn commission_of(&self, amount: Tokens) -> Tokens {
+        let e8s = (amount.e8s() as u128 * self.commission_rate_bps as u128)
+            .div_ceil(BASIS_POINTS as u128);
+        Tokens::from_e8s(e8s.try_into().unwrap_or(u64::MAX))
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |reason: &str| Err(Error::InvalidSettings(reason.into()));
+
+        if self.max_apps_per_developer == 0 {
+            return invalid("`max_apps_per_developer` should be greater than zero");
+        }
+        if self.commission_rate_bps as u64 > BASIS_POINTS {
+            return invalid("`commission_rate_bps` should be at most 10000");
+        }
+        if self.exchange_rate_timeout.is_zero() {
+            return invalid("`exchange_rate_timeout` should be greater than zero");
+        }
+        if !(0.0..=1.0).contains(&self.exchange_rate_tolerance)
+            || self.exchange_rate_tolerance == 0.0
+        {
+            return invalid("`exchange_rate_tolerance` should be greater than 0 and at most 1");
+        }
+        if self.max_wasm_module_size == 0 || self.max_wasm_module_size > MAX_UPLOAD_SIZE {
+            return invalid("`max_wasm_module_size` should be between 1 byte and 100 MiB");
+        }
+        if self.cycles_request_amount == 0 {
+            return invalid("`cycles_request_amount` should be greater than zero");
+        }
+        // The cycles controller of new apps could not request cycles otherwise.
+        if self.max_cycles_per_day < self.cycles_request_amount {
+            return invalid("`max_cycles_per_day` should be at least `cycles_request_amount`");
+        }
+        if self.cycles_request_cooldown_seconds == 0 {
+            return invalid("`cycles_request_cooldown_seconds` should be greater than zero");
+        }
+        Ok(())
+    }
+}
+
+impl Versioned for Settings {
+    const NAME: &'static str = "settings";
+    const VERSION: u8 = 1;
 }
 
 #[derive(CandidType, Deserialize)]
 pub struct InitArgs {
     pub minimum_escrow_balance_for_deploy: Tokens,
     pub max_apps_per_developer: usize,
-    pub commition_rate: f32,
+    pub commission_rate_bps: u32,
+    pub treasury_account: AccountIdentifier,
     pub exchange_rate_timeout_seconds: u64,
+    // `DEFAULT_EXCHANGE_RATE_TOLERANCE` is used when not set.
+    pub exchange_rate_tolerance: Option<f32>,
+    pub max_wasm_module_size: u64,
+    pub cycles_request_threshold: u64,
+    pub cycles_request_amount: u64,
+    // Cycles policy of new apps, along with `cycles_request_amount` per request.
+    pub max_cycles_per_day: u64,
+    pub cycles_request_cooldown_seconds: u64,
+    pub manager_canisters: Vec<Principal>,
+    pub service_prices: ServicePrices,
+    // Mainnet canisters are used when not set.
+    pub ledger_canister_id: Option<Principal>,
+    pub cycles_minting_canister_id: Option<Principal>,
+    pub exchange_rate_canister_id: Option<Principal>,
+}
+
+/// Settings to override, the ones left empty keep their current value.
+#[derive(CandidType, Deserialize)]
+pub struct SettingsUpdate {
+    pub minimum_escrow_balance_for_deploy: Option<Tokens>,
+    pub max_apps_per_developer: Option<usize>,
+    pub commission_rate_bps: Option<u32>,
+    pub treasury_account: Option<AccountIdentifier>,
+    pub exchange_rate_timeout_seconds: Option<u64>,
+    pub exchange_rate_tolerance: Option<f32>,
+    pub max_wasm_module_size: Option<u64>,
+    pub cycles_request_threshold: Option<u64>,
+    pub cycles_request_amount: Option<u64>,
+    pub max_cycles_per_day: Option<u64>,
+    pub cycles_request_cooldown_seconds: Option<u64>,
+    pub manager_canisters: Option<Vec<Principal>>,
+    pub service_prices: Option<ServicePrices>,
+    pub ledger_canister_id: Option<Principal>,
+    pub cycles_minting_canister_id: Option<Principal>,
+    pub exchange_rate_canister_id: Option<Principal>,
+}
+
+pub fn ensure_caller_is_admin() -> Result<()> {
+    let caller = ic_cdk::caller();
+    // Controllers are always admins, so the admins can not lock themselves out.
+    if ic_cdk::api::is_controller(&caller) || STATE.with_borrow(|s| s.is_admin(&caller)) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized)
+    }
+}
+
+impl From<InitArgs> for Settings {
+    fn from(init_args: InitArgs) -> Self {
+        Self {
+            minimum_escrow_balance_for_deploy: init_args.minimum_escrow_balance_for_deploy,
+            max_apps_per_developer: init_args.max_apps_per_developer,
+            commission_rate_bps: init_args.commission_rate_bps,
+            treasury_account: init_args.treasury_account,
+            exchange_rate_timeout: Duration::from_secs(init_args.exchange_rate_timeout_seconds),
+            exchange_rate_tolerance: init_args
+                .exchange_rate_tolerance
+                .unwrap_or(DEFAULT_EXCHANGE_RATE_TOLERANCE),
+            max_wasm_module_size: init_args.max_wasm_module_size,
+            cycles_request_threshold: init_args.cycles_request_threshold,
+            cycles_request_amount: init_args.cycles_request_amount,
+            max_cycles_per_day: init_args.max_cycles_per_day,
+            cycles_request_cooldown_seconds: init_args.cycles_request_cooldown_seconds,
+            manager_canisters: init_args.manager_canisters,
+            service_prices: init_args.service_prices,
+            ledger_canister_id: init_args
+                .ledger_canister_id
+                .unwrap_or(MAINNET_LEDGER_CANISTER_ID),
+            cycles_minting_canister_id: init_args
+                .cycles_minting_canister_id
+                .unwrap_or(MAINNET_CYCLE_MINTER_CANISTER_ID),
+            exchange_rate_canister_id: init_args
+                .exchange_rate_canister_id
+                .unwrap_or(MAINNET_EXCHANGE_RATE_CANISTER_ID),
+        }
+    }
+}
+
+// Upgrading from a version keeping the settings on the heap, they have to be given in full.
+impl TryFrom<SettingsUpdate> for InitArgs {
+    type Error = Error;
+
+    fn try_from(update: SettingsUpdate) -> Result<Self> {
+        fn required<T>(setting: Option<T>, name: &str) -> Result<T> {
+            setting.ok_or_else(|| {
+                Error::InvalidSettings(format!(
+                    "`{name}` is required, there are no settings to keep yet"
+                ))
+            })
+        }
+
+        Ok(Self {
+            minimum_escrow_balance_for_deploy: required(
+                update.minimum_escrow_balance_for_deploy,
+                "minimum_escrow_balance_for_deploy",
+            )?,
+            max_apps_per_developer: required(
+                update.max_apps_per_developer,
+                "max_apps_per_developer",
+            )?,
+            commission_rate_bps: required(update.commission_rate_bps, "commission_rate_bps")?,
+            treasury_account: required(update.treasury_account, "treasury_account")?,
+            exchange_rate_timeout_seconds: required(
+                update.exchange_rate_timeout_seconds,
+                "exchange_rate_timeout_seconds",
+            )?,
+            exchange_rate_tolerance: update.exchange_rate_tolerance,
+            max_wasm_module_size: required(update.max_wasm_module_size, "max_wasm_module_size")?,
+            cycles_request_threshold: required(
+                update.cycles_request_threshold,
+                "cycles_request_threshold",
+            )?,
+            cycles_request_amount: required(update.cycles_request_amount, "cycles_request_amount")?,
+            max_cycles_per_day: required(update.max_cycles_per_day, "max_cycles_per_day")?,
+            cycles_request_cooldown_seconds: required(
+                update.cycles_request_cooldown_seconds,
+                "cycles_request_cooldown_seconds",
+            )?,
+            manager_canisters: required(update.manager_canisters, "manager_canisters")?,
+            service_prices: required(update.service_prices, "service_prices")?,
+            ledger_canister_id: update.ledger_canister_id,
+            cycles_minting_canister_id: update.cycles_minting_canister_id,
+            exchange_rate_canister_id: update.exchange_rate_canister_id,
+        })
+    }
 }
 
 #[ic_cdk::init]
 fn init_canister(init_args: crate::settings::InitArgs) {
-    let settings = Settings {
-        minimum_escrow_balance_for_deploy: init_args.minimum_escrow_balance_for_deploy,
-        max_apps_per_developer: init_args.max_apps_per_developer,
-        commition_rate: init_args.commition_rate,
-        exchange_rate_timeout: Duration::from_secs(init_args.exchange_rate_timeout_seconds),
-    };
-
-    STATE.with_borrow_mut(|s| s.init_settings(settings));
+    let settings = Settings::from(init_args);
+    if let Err(e) = settings.validate() {
+        ic_cdk::trap(&format!("Invalid init arguments: {e:?}"));
+    }
+
+    STATE.with_borrow_mut(|s| {
+        s.init_settings(settings);
+        s.add_admin(ic_cdk::caller());
+    });
+    start_retry_timer();
+    start_settlement_timer();
+    start_usage_settlement_timer();
+    start_exchange_rate_refresh_timer();
+    start_upload_purge_timer();
+}
+
+// Settings are kept in stable memory, so upgrading only needs to apply the overrides, if any.
+// Upgrading from a version without settings in stable memory needs all of them, like on init.
+#[ic_cdk::post_upgrade]
+fn post_upgrade_canister(update: Option<crate::settings::SettingsUpdate>) {
+    let result = STATE.with_borrow_mut(|s| {
+        if s.has_settings() {
+            return update.map_or(Ok(()), |update| s.update_settings(update));
+        }
+        let update = update.ok_or_else(|| {
+            Error::InvalidSettings(
+                "All the settings are required, there are no settings to keep yet".to_string(),
+            )
+        })?;
+        let settings = Settings::from(InitArgs::try_from(update)?);
+        settings.validate()?;
+        s.init_settings(settings);
+        Ok(())
+    });
+    if let Err(e) = result {
+        ic_cdk::trap(&format!("Invalid upgrade arguments: {e:?}"));
+    }
+    STATE.with_borrow_mut(|s| s.migrate_developer_apps());
+    // The usages can be too many to move in a single message.
+    start_app_usages_migration(None);
+    // Timers do not survive upgrades.
+    start_retry_timer();
+    start_settlement_timer();
+    start_usage_settlement_timer();
+    start_exchange_rate_refresh_timer();
+    start_upload_purge_timer();
+}
+
+#[ic_cdk::query]
+fn get_settings() -> Result<crate::settings::Settings> {
+    ensure_caller_is_admin()?;
+    Ok(STATE.with_borrow(|s| s.settings().clone()))
+}
+
+#[ic_cdk::update]
+fn update_settings(update: crate::settings::SettingsUpdate) -> Result<()> {
+    ensure_caller_is_admin()?;
+    STATE.with_borrow_mut(|s| s.update_settings(update))
+}
+
+#[ic_cdk::update]
+fn add_admin(principal: candid::Principal) -> Result<()> {
+    ensure_caller_is_admin()?;
+    STATE.with_borrow_mut(|s| s.add_admin(principal));
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn remove_admin(principal: candid::Principal) -> Result<()> {
+    ensure_caller_is_admin()?;
+    STATE.with_borrow_mut(|s| s.remove_admin(&principal));
+    Ok(())
 }
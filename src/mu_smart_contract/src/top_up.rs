@@ -0,0 +1,161 @@
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use candid::CandidType;
+use candid::Deserialize;
+use ic_ledger_types::BlockIndex;
+use ic_ledger_types::Timestamp;
+use ic_ledger_types::Tokens;
+
+use crate::app::AppID;
+use crate::app::AppUsage;
+use crate::app::UsageKind;
+use crate::commission::charge_commission;
+use crate::developer::Developer;
+use crate::developer::DeveloperID;
+use crate::error::Error;
+use crate::guard::Guard;
+use crate::memory::STATE;
+use crate::schema::Versioned;
+use crate::utils::exchange::notify_top_up;
+use crate::utils::exchange::NotifyError;
+use crate::Result;
+
+const RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+thread_local! {
+    // Top-ups being notified right now, so the retry timer does not race the original call.
+    static NOTIFYING: RefCell<BTreeSet<BlockIndex>> = RefCell::default();
+}
+
+/// A top-up paid to the cycles minting canister, waiting to be converted into cycles.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PendingTopUp {
+    pub block_index: BlockIndex,
+    pub developer_id: DeveloperID,
+    // Not set for the top-ups of this canister, paying for the creation of an app canister.
+    pub app_id: Option<AppID>,
+    pub amount: Tokens,
+    pub created_at: Timestamp,
+    pub attempts: u32,
+    pub status: TopUpStatus,
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq)]
+pub enum TopUpStatus {
+    /// Notifying the cycles minting canister is retried periodically.
+    Pending,
+    /// The cycles minting canister will never mint the cycles of the top-up, nor return its
+    /// tokens. Kept for the records of the developer.
+    Failed { reason: String },
+}
+
+impl Versioned for PendingTopUp {
+    const NAME: &'static str = "pending top-up";
+    const VERSION: u8 = 1;
+}
+
+/// Notify the cycles minting canister of a pending top-up. Refunded top-ups are dropped, the
+/// tokens are back in the escrow, top-ups the cycles minting canister can never accept are marked
+/// as failed, and the others are retried.
+pub async fn notify_pending_top_up(top_up: &PendingTopUp) -> Result<u128> {
+    let block_index = top_up.block_index;
+    let Some(_guard) = Guard::new(&NOTIFYING, block_index) else {
+        return Err(Error::TopUpPending { block_index });
+    };
+
+    let canister_id = top_up.app_id.unwrap_or_else(ic_cdk::id);
+    let result = notify_top_up(canister_id, block_index).await;
+    STATE.with_borrow_mut(|s| match result {
+        Ok(Ok(cycles)) => Ok(cycles),
+        Ok(Err(NotifyError::Refunded { reason, .. })) => {
+            s.remove_pending_top_up(block_index);
+            Err(Error::TopUpRefunded { reason })
+        }
+        // The cycles minting canister may still mint the cycles, or refund the tokens, so these are
+        // retried until it answers for good.
+        Ok(Err(NotifyError::Processing | NotifyError::Other { .. })) | Err(_) => {
+            s.update_pending_top_up(block_index, |t| t.attempts += 1);
+            Err(Error::TopUpPending { block_index })
+        }
+        Ok(Err(e @ (NotifyError::InvalidTransaction(_) | NotifyError::TransactionTooOld(_)))) => {
+            let reason = format!("{e:?}");
+            s.update_pending_top_up(block_index, |t| {
+                t.attempts += 1;
+                t.status = TopUpStatus::Failed {
+                    reason: reason.clone(),
+                };
+            });
+            Err(Error::Internal(format!(
+                "Notify top-up failed, error: {reason}"
+            )))
+        }
+    })
+}
+
+/// Periodically retry the top-ups that could not be notified when they were paid.
+pub fn start_retry_timer() {
+    ic_cdk_timers::set_timer_interval(RETRY_INTERVAL, || ic_cdk::spawn(retry_pending_top_ups()));
+}
+
+async fn retry_pending_top_ups() {
+    let block_indexes = STATE.with_borrow(|s| s.get_pending_top_ups_to_retry());
+    for block_index in block_indexes {
+        // The top-up may have been completed since, by the call that paid for it.
+        let Some(top_up) = STATE.with_borrow(|s| s.get_pending_top_up(block_index)) else {
+            continue;
+        };
+        if let Ok(cycles) = notify_pending_top_up(&top_up).await {
+            complete_top_up(top_up, cycles).await;
+        }
+    }
+}
+
+async fn complete_top_up(top_up: PendingTopUp, cycles: u128) {
+    let Some(app_id) = top_up.app_id else {
+        // The deploy paying for the cycles already failed, they are kept for the next one. The
+        // top-up is retried when they can not be recorded.
+        STATE.with_borrow_mut(|s| {
+            if s.add_deploy_credit(top_up.developer_id, cycles, top_up.amount)
+                .is_ok()
+            {
+                s.remove_pending_top_up(top_up.block_index);
+            }
+        });
+        return;
+    };
+    STATE.with_borrow_mut(|s| s.remove_pending_top_up(top_up.block_index));
+
+    let Ok(developer) = STATE.with_borrow(|s| s.get_developer(&top_up.developer_id)) else {
+        return;
+    };
+    let commission =
+        charge_commission(top_up.developer_id, developer.escrow_account, top_up.amount).await;
+    let usage = AppUsage {
+        kind: UsageKind::CyclesCharge { cylces: cycles },
+        timestamp: Timestamp {
+            timestamp_nanos: ic_cdk::api::time(),
+        },
+        amount: top_up.amount,
+        commission,
+        is_paid: true,
+    };
+    let spent = top_up.amount + commission;
+    STATE.with_borrow_mut(|s| {
+        let now = ic_cdk::api::time();
+        // The app may have been removed while the top-up was pending.
+        if s.register_usage(app_id, usage).is_ok() {
+            s.record_app_spending(app_id, spent, now);
+            // Counted against the cycles policy of the app, as if the request had succeeded.
+            let cycles = cycles.try_into().unwrap_or(u64::MAX);
+            let _ = s.update_app(app_id, |app| app.cycles_requests.record(cycles, now));
+        }
+    });
+}
+
+#[ic_cdk::query]
+fn get_pending_top_ups() -> Result<Vec<crate::top_up::PendingTopUp>> {
+    let (developer_id, _) = Developer::get_caller_developer_account()?;
+    Ok(STATE.with_borrow(|s| s.get_pending_top_ups_of_developer(&developer_id)))
+}
@@ -0,0 +1,114 @@
+use std::time::Duration;
+
+use candid::CandidType;
+use candid::Deserialize;
+use ic_ledger_types::Timestamp;
+
+use crate::developer::Developer;
+use crate::developer::DeveloperID;
+use crate::error::Error;
+use crate::memory::STATE;
+use crate::schema::Versioned;
+use crate::Result;
+
+pub type UploadID = u64;
+
+// Wasm modules can not be bigger than this when installed from chunks.
+pub const MAX_UPLOAD_SIZE: u64 = 100 * 1024 * 1024;
+
+// Uploads in progress or not deployed yet, as each one can hold up to `MAX_UPLOAD_SIZE` bytes.
+pub const MAX_UPLOADS_PER_DEVELOPER: u64 = 3;
+
+// Uploads are dropped, along with their chunks, when not deployed within a day.
+pub const UPLOAD_EXPIRY_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(CandidType, Deserialize)]
+pub struct Upload {
+    pub developer_id: DeveloperID,
+    pub size: u64,
+    pub received: u64,
+    pub chunks_count: u32,
+    pub created_at: Timestamp,
+    pub finalized: bool,
+}
+
+impl Versioned for Upload {
+    const NAME: &'static str = "upload";
+    const VERSION: u8 = 1;
+}
+
+impl Upload {
+    pub fn is_expired(&self, now: u64) -> bool {
+        is_upload_expired(self.created_at.timestamp_nanos, now)
+    }
+}
+
+pub fn is_upload_expired(created_at: u64, now: u64) -> bool {
+    now.saturating_sub(created_at) >= UPLOAD_EXPIRY_NANOS
+}
+
+/// Periodically remove the uploads that expired.
+pub fn start_upload_purge_timer() {
+    ic_cdk_timers::set_timer_interval(PURGE_INTERVAL, || {
+        STATE.with_borrow_mut(|s| s.remove_expired_uploads(ic_cdk::api::time()))
+    });
+}
+
+/// Start a new upload session for an app package of `size` bytes, to be finalized and deployed
+/// within a day.
+#[ic_cdk::update]
+fn begin_upload(size: u64) -> Result<crate::upload::UploadID> {
+    let (developer_id, _) = Developer::get_caller_developer_account()?;
+    if size > MAX_UPLOAD_SIZE {
+        return Err(Error::UploadSizeLimitExceeded {
+            max: MAX_UPLOAD_SIZE,
+        });
+    }
+    let now = ic_cdk::api::time();
+    if STATE.with_borrow(|s| s.uploads_count_of(&developer_id, now)) >= MAX_UPLOADS_PER_DEVELOPER {
+        return Err(Error::TooManyUploads {
+            max: MAX_UPLOADS_PER_DEVELOPER,
+        });
+    }
+
+    let upload = Upload {
+        developer_id,
+        size,
+        received: 0,
+        chunks_count: 0,
+        created_at: Timestamp {
+            timestamp_nanos: now,
+        },
+        finalized: false,
+    };
+
+    Ok(STATE.with_borrow_mut(|s| s.begin_upload(upload)))
+}
+
+#[ic_cdk::update]
+fn append_upload_chunk(
+    upload_id: crate::upload::UploadID,
+    chunk: serde_bytes::ByteBuf,
+) -> Result<()> {
+    let (developer_id, _) = Developer::get_caller_developer_account()?;
+    STATE.with_borrow_mut(|s| s.append_upload_chunk(&developer_id, upload_id, chunk.into_vec()))
+}
+
+/// Close the upload session, the uploaded data should match the `sha256` hash.
+#[ic_cdk::update]
+fn finalize_upload(upload_id: crate::upload::UploadID, sha256: serde_bytes::ByteBuf) -> Result<()> {
+    let (developer_id, _) = Developer::get_caller_developer_account()?;
+    STATE.with_borrow_mut(|s| s.finalize_upload(&developer_id, upload_id, &sha256))
+}
+
+#[ic_cdk::update]
+fn cancel_upload(upload_id: crate::upload::UploadID) -> Result<()> {
+    let (developer_id, _) = Developer::get_caller_developer_account()?;
+    STATE.with_borrow_mut(|s| {
+        s.get_upload_of_developer(&developer_id, upload_id)?;
+        s.remove_upload(upload_id);
+        Ok(())
+    })
+}
@@ -0,0 +1,334 @@
+use std::collections::BTreeMap;
+
+use candid::CandidType;
+use candid::Deserialize;
+use ic_ledger_types::AccountIdentifier;
+use ic_ledger_types::Subaccount;
+use ic_ledger_types::Timestamp;
+use ic_ledger_types::Tokens;
+use ic_ledger_types::DEFAULT_SUBACCOUNT;
+
+use crate::app::AppID;
+use crate::app::AppUsage;
+use crate::app::UsageKind;
+use crate::commission::pay_commission;
+use crate::commission::MAX_OPERATION_AGE_NANOS;
+use crate::commission::SETTLEMENT_INTERVAL;
+use crate::developer::DeveloperID;
+use crate::error::Error;
+use crate::guard::OperationGuard;
+use crate::journal::journal_entry;
+use crate::journal::JournalKey;
+use crate::journal::Operation;
+use crate::memory::UsagePosition;
+use crate::memory::STATE;
+use crate::schema::Versioned;
+use crate::utils::transfer_tokens;
+use crate::Result;
+
+const MILLION: u128 = 1_000_000;
+const GIB: u128 = 1024 * 1024 * 1024;
+
+/// Usage of an additional service by an app, since the previous report of the manager canister.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum ServiceUsage {
+    KvStore {
+        reads: u64,
+        writes: u64,
+        stored_bytes: u64,
+    },
+    BlobStorage {
+        stored_bytes: u64,
+        downloaded_bytes: u64,
+    },
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct UsageReport {
+    pub app_id: AppID,
+    pub usage: ServiceUsage,
+}
+
+/// Prices of the additional services, stored bytes are charged for each report.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ServicePrices {
+    pub kv_store_reads_per_million: Tokens,
+    pub kv_store_writes_per_million: Tokens,
+    pub kv_store_storage_per_gib: Tokens,
+    pub blob_storage_storage_per_gib: Tokens,
+    pub blob_storage_download_per_gib: Tokens,
+}
+
+impl ServicePrices {
+    pub fn price(&self, usage: &ServiceUsage) -> Tokens {
+        let e8s = match *usage {
+            ServiceUsage::KvStore {
+                reads,
+                writes,
+                stored_bytes,
+            } => {
+                cost(reads, self.kv_store_reads_per_million, MILLION)
+                    + cost(writes, self.kv_store_writes_per_million, MILLION)
+                    + cost(stored_bytes, self.kv_store_storage_per_gib, GIB)
+            }
+            ServiceUsage::BlobStorage {
+                stored_bytes,
+                downloaded_bytes,
+            } => {
+                cost(stored_bytes, self.blob_storage_storage_per_gib, GIB)
+                    + cost(downloaded_bytes, self.blob_storage_download_per_gib, GIB)
+            }
+        };
+        Tokens::from_e8s(e8s.try_into().unwrap_or(u64::MAX))
+    }
+}
+
+// Rounded up, so usage is never free because of a small report.
+fn cost(units: u64, price: Tokens, per: u128) -> u128 {
+    (units as u128 * price.e8s() as u128).div_ceil(per)
+}
+
+struct UsageCharge {
+    app_id: AppID,
+    usage: ServiceUsage,
+    amount: Tokens,
+    commission: Tokens,
+    // Charges exceeding the budget of the app are recorded as unpaid.
+    within_budget: bool,
+}
+
+/// A usage charge whose transfer failed, its within budget usages are recorded as unpaid. Retried
+/// with the same operation by the settlement timer, and by retries of its report, until it is
+/// paid.
+#[derive(CandidType, Deserialize)]
+pub struct UnpaidUsage {
+    pub journal_key: JournalKey,
+    pub operation: Operation,
+    pub amount: Tokens,
+    pub commission: Tokens,
+    pub usages: Vec<(AppID, UsagePosition)>,
+}
+
+impl Versioned for UnpaidUsage {
+    const NAME: &'static str = "unpaid usage";
+    const VERSION: u8 = 1;
+}
+
+/// Charge the developers for the additional services their apps used, reported by one of the
+/// mu manager canisters. The usages are recorded even if the escrow can not pay for them, or if
+/// they exceed the budget of the app.
+///
+/// Retrying a report with the same `report_id` (within 24 hours) only charges the developers that
+/// were not charged yet, reusing it for another report fails with `IdempotencyKeyConflict`.
+#[ic_cdk::update]
+async fn report_usage(report_id: String, reports: Vec<crate::usage::UsageReport>) -> Result<()> {
+    let caller = ic_cdk::caller();
+    let (prices, is_manager) = STATE.with_borrow(|s| {
+        (
+            s.settings().service_prices.clone(),
+            s.settings().manager_canisters.contains(&caller),
+        )
+    });
+    if !is_manager {
+        return Err(Error::Unauthorized);
+    }
+
+    // Reports are validated before charging anything, then charged once per developer.
+    let now = ic_cdk::api::time();
+    let mut charges = BTreeMap::<DeveloperID, (Subaccount, Vec<UsageCharge>)>::new();
+    // Charged in this report for each app, for its budget.
+    let mut charged = BTreeMap::<AppID, Tokens>::new();
+    STATE.with_borrow_mut(|s| {
+        for report in reports {
+            let app = s.get_app(&report.app_id)?;
+            let developer = s.get_developer(&app.developer_id)?;
+            let amount = prices.price(&report.usage);
+            let commission = s.settings().commission_of(amount);
+
+            let app_charged = charged.entry(report.app_id).or_insert(Tokens::from_e8s(0));
+            let within_budget = s
+                .check_app_budget(report.app_id, *app_charged + amount + commission, now)
+                .is_ok();
+            if within_budget {
+                *app_charged += amount + commission;
+            }
+
+            charges
+                .entry(app.developer_id)
+                .or_insert_with(|| (developer.escrow_account, Vec::new()))
+                .1
+                .push(UsageCharge {
+                    app_id: report.app_id,
+                    usage: report.usage,
+                    amount,
+                    commission,
+                    within_budget,
+                });
+        }
+        Ok::<_, Error>(())
+    })?;
+
+    // The charge of each developer is journaled under the report ID, so a retried report replays
+    // the transfers that may have been executed and skips the developers already charged.
+    let mut journaled = Vec::new();
+    for (developer_id, (escrow_account, usages)) in charges {
+        let args = (
+            caller,
+            usages
+                .iter()
+                .map(|c| (c.app_id, c.usage.clone()))
+                .collect::<Vec<_>>(),
+        );
+        let (key, entry) = journal_entry(developer_id, "report_usage", &report_id, &args)?;
+        if !entry.completed {
+            journaled.push((developer_id, escrow_account, usages, key, entry.operation));
+        }
+    }
+
+    // Developers that are busy or can not pay are left for a retry of the report, or for the
+    // settlement timer, the others are still charged and the first error is returned at the end.
+    let mut error = None;
+    for (developer_id, escrow_account, usages, key, operation) in journaled {
+        let result = match OperationGuard::new(developer_id) {
+            Ok(_guard) => {
+                charge_usages(developer_id, escrow_account, usages, key, operation, now).await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            error.get_or_insert(e);
+        }
+    }
+
+    error.map_or(Ok(()), Err)
+}
+
+async fn charge_usages(
+    developer_id: DeveloperID,
+    escrow_account: Subaccount,
+    usages: Vec<UsageCharge>,
+    key: JournalKey,
+    operation: Operation,
+    now: u64,
+) -> Result<()> {
+    // Charged already, the transfer failed and is retried with its original operation.
+    if let Some(unpaid) = STATE.with_borrow(|s| s.get_unpaid_usage(developer_id, operation.memo)) {
+        return settle_unpaid_usage(developer_id, unpaid).await;
+    }
+
+    let (total, total_commission) = usages.iter().filter(|c| c.within_budget).fold(
+        (Tokens::from_e8s(0), Tokens::from_e8s(0)),
+        |(total, total_commission), charge| {
+            (total + charge.amount, total_commission + charge.commission)
+        },
+    );
+    let result = if total == Tokens::from_e8s(0) {
+        Ok(None)
+    } else {
+        let to = AccountIdentifier::new(&ic_cdk::id(), &DEFAULT_SUBACCOUNT);
+        transfer_tokens(escrow_account, to, total, operation)
+            .await
+            .map(Some)
+    };
+    let is_paid = result.is_ok();
+
+    let timestamp = Timestamp {
+        timestamp_nanos: ic_cdk::api::time(),
+    };
+    // Apps removed while their developer was charged can not record their usage, the first such
+    // error is returned once the others are recorded.
+    let mut error = None;
+    let mut unpaid_usages = Vec::new();
+    STATE.with_borrow_mut(|s| {
+        for charge in usages {
+            if is_paid && charge.within_budget {
+                s.record_app_spending(charge.app_id, charge.amount + charge.commission, now);
+            }
+            let usage = AppUsage {
+                kind: UsageKind::AdditionalServices {
+                    usage: charge.usage,
+                },
+                timestamp,
+                amount: charge.amount,
+                commission: charge.commission,
+                is_paid: is_paid && charge.within_budget,
+            };
+            match s.register_usage(charge.app_id, usage) {
+                Ok(position) if !is_paid && charge.within_budget => {
+                    unpaid_usages.push((charge.app_id, position))
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+
+        // Recorded along with the completion of the journal entry, or of the unpaid charge, so
+        // they are never recorded twice. The journal entry is left open until the charge is paid.
+        match result {
+            Ok(block_index) => s.complete_journal_entry(&key, block_index),
+            Err(_) => s.insert_unpaid_usage(
+                developer_id,
+                UnpaidUsage {
+                    journal_key: key,
+                    operation,
+                    amount: total,
+                    commission: total_commission,
+                    usages: unpaid_usages,
+                },
+            ),
+        }
+    });
+
+    result?;
+    // Usages that could not be paid are charged their commission once they are.
+    pay_commission(developer_id, escrow_account, total_commission).await;
+    error.map_or(Ok(()), Err)
+}
+
+/// Periodically retry the usage charges that could not be paid when they were reported.
+pub fn start_usage_settlement_timer() {
+    ic_cdk_timers::set_timer_interval(
+        SETTLEMENT_INTERVAL,
+        || ic_cdk::spawn(settle_unpaid_usages()),
+    );
+}
+
+async fn settle_unpaid_usages() {
+    let unpaid_usages = STATE.with_borrow(|s| s.get_unpaid_usages());
+    for (developer_id, _, unpaid) in unpaid_usages {
+        // Settled with the next run when the developer is spending from their escrow right now.
+        let Ok(_guard) = OperationGuard::new(developer_id) else {
+            continue;
+        };
+        let _ = settle_unpaid_usage(developer_id, unpaid).await;
+    }
+}
+
+// Expects the operation guard of the developer to be held.
+async fn settle_unpaid_usage(developer_id: DeveloperID, unpaid: UnpaidUsage) -> Result<()> {
+    let developer = STATE.with_borrow(|s| s.get_developer(&developer_id))?;
+    let memo = unpaid.operation.memo;
+    let mut operation = unpaid.operation;
+    if ic_cdk::api::time().saturating_sub(operation.created_at_time) > MAX_OPERATION_AGE_NANOS {
+        operation = Operation::with_memo(memo);
+        STATE.with_borrow_mut(|s| {
+            s.update_unpaid_usage(developer_id, memo, |u| u.operation = operation)
+        });
+    }
+
+    let to = AccountIdentifier::new(&ic_cdk::id(), &DEFAULT_SUBACCOUNT);
+    let block_index =
+        transfer_tokens(developer.escrow_account, to, unpaid.amount, operation).await?;
+    STATE.with_borrow_mut(|s| {
+        let now = ic_cdk::api::time();
+        for (app_id, position) in unpaid.usages {
+            s.mark_usage_paid(app_id, position, now);
+        }
+        s.complete_journal_entry(&unpaid.journal_key, Some(block_index));
+        s.remove_unpaid_usage(developer_id, memo);
+    });
+    pay_commission(developer_id, developer.escrow_account, unpaid.commission).await;
+    Ok(())
+}
@@ -1,3 +1,4 @@
+use candid::Nat;
 use ic_ledger_types::account_balance;
 use ic_ledger_types::transfer;
 use ic_ledger_types::AccountBalanceArgs;
@@ -5,22 +6,32 @@ use ic_ledger_types::AccountIdentifier;
 use ic_ledger_types::BlockIndex;
 use ic_ledger_types::Memo;
 use ic_ledger_types::Subaccount;
+use ic_ledger_types::Timestamp;
 use ic_ledger_types::Tokens;
 use ic_ledger_types::TransferArgs;
+use ic_ledger_types::TransferError;
 use ic_ledger_types::DEFAULT_FEE;
-use ic_ledger_types::MAINNET_LEDGER_CANISTER_ID;
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::transfer::TransferArg;
+use icrc_ledger_types::icrc1::transfer::TransferError as Icrc1TransferError;
+use icrc_ledger_types::icrc2::transfer_from::TransferFromArgs;
+use icrc_ledger_types::icrc2::transfer_from::TransferFromError;
 
 use crate::error::Error;
+use crate::journal::Operation;
+use crate::memory::STATE;
 use crate::Result;
 
 pub mod exchange;
+pub mod management;
 
 pub async fn get_developer_escrow_balance(subaccount: &Subaccount) -> Result<Tokens> {
     let args = AccountBalanceArgs {
         account: AccountIdentifier::new(&ic_cdk::id(), subaccount),
     };
 
-    account_balance(MAINNET_LEDGER_CANISTER_ID, args)
+    let ledger_canister_id = STATE.with_borrow(|s| s.settings().ledger_canister_id);
+    account_balance(ledger_canister_id, args)
         .await
         .map_err(|e| {
             Error::Internal(format!(
@@ -30,22 +41,27 @@ pub async fn get_developer_escrow_balance(subaccount: &Subaccount) -> Result<Tok
         })
 }
 
+/// Transfer tokens as part of `operation`, a transfer the ledger already executed for the same
+/// operation returns the original block.
 pub async fn transfer_tokens(
     from_subaccount: Subaccount,
     to: AccountIdentifier,
     amount: Tokens,
-    memo: Memo,
+    operation: Operation,
 ) -> Result<BlockIndex> {
     let args = TransferArgs {
-        memo,
+        memo: Memo(operation.memo),
         amount,
         fee: DEFAULT_FEE,
         from_subaccount: Some(from_subaccount),
         to,
-        created_at_time: None,
+        created_at_time: Some(Timestamp {
+            timestamp_nanos: operation.created_at_time,
+        }),
     };
 
-    transfer(MAINNET_LEDGER_CANISTER_ID, args)
+    let ledger_canister_id = STATE.with_borrow(|s| s.settings().ledger_canister_id);
+    transfer(ledger_canister_id, args)
         .await
         .map_err(|e| {
             Error::Internal(format!(
@@ -53,5 +69,88 @@ pub async fn transfer_tokens(
                 e.0, e.1
             ))
         })?
-        .map_err(|e| Error::Internal(format!("Transfer failed, reason: {e}")))
+        .or_else(|e| match e {
+            TransferError::TxDuplicate { duplicate_of } => Ok(duplicate_of),
+            e => Err(Error::Internal(format!("Transfer failed, reason: {e}"))),
+        })
+}
+
+pub async fn icrc1_transfer_tokens(
+    from_subaccount: Subaccount,
+    to: Account,
+    amount: Nat,
+    operation: Operation,
+) -> Result<BlockIndex> {
+    let args = TransferArg {
+        from_subaccount: Some(from_subaccount.0),
+        to,
+        fee: None,
+        created_at_time: Some(operation.created_at_time),
+        memo: Some(operation.memo.into()),
+        amount,
+    };
+
+    let ledger_canister_id = STATE.with_borrow(|s| s.settings().ledger_canister_id);
+    ic_cdk::call::<_, (std::result::Result<Nat, Icrc1TransferError>,)>(
+        ledger_canister_id,
+        "icrc1_transfer",
+        (args,),
+    )
+    .await
+    .map_err(|e| {
+        Error::Internal(format!(
+            "Failed to call icrc1_transfer on ic_ledger, error_code: {:?}, reason: {}",
+            e.0, e.1
+        ))
+    })?
+    .0
+    .or_else(|e| match e {
+        Icrc1TransferError::Duplicate { duplicate_of } => Ok(duplicate_of),
+        e => Err(Error::Internal(format!("Transfer failed, reason: {e}"))),
+    })
+    .and_then(block_index_from_nat)
+}
+
+/// Transfer tokens out of `from`, within the allowance it approved for this canister.
+pub async fn icrc2_transfer_from_tokens(
+    from: Account,
+    to: Account,
+    amount: Nat,
+    operation: Operation,
+) -> Result<BlockIndex> {
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from,
+        to,
+        amount,
+        fee: None,
+        memo: Some(operation.memo.into()),
+        created_at_time: Some(operation.created_at_time),
+    };
+
+    let ledger_canister_id = STATE.with_borrow(|s| s.settings().ledger_canister_id);
+    ic_cdk::call::<_, (std::result::Result<Nat, TransferFromError>,)>(
+        ledger_canister_id,
+        "icrc2_transfer_from",
+        (args,),
+    )
+    .await
+    .map_err(|e| {
+        Error::Internal(format!(
+            "Failed to call icrc2_transfer_from on ic_ledger, error_code: {:?}, reason: {}",
+            e.0, e.1
+        ))
+    })?
+    .0
+    .or_else(|e| match e {
+        TransferFromError::Duplicate { duplicate_of } => Ok(duplicate_of),
+        e => Err(Error::Internal(format!("Transfer failed, reason: {e}"))),
+    })
+    .and_then(block_index_from_nat)
+}
+
+// Block indexes of the ICP ledger always fit in a `u64`.
+fn block_index_from_nat(block_index: Nat) -> Result<BlockIndex> {
+    u64::try_from(block_index.0)
+        .map_err(|e| Error::Internal(format!("Invalid block index, reason: {e}")))
 }
@@ -1,10 +1,16 @@
-use std::time::Instant;
+use std::time::Duration;
 
-use crate::app::AppID;
 use crate::declarations::exchange_rate_canister as exchange;
 
+use crate::app::AppID;
+use crate::developer::DeveloperID;
 use crate::error::Error;
+use crate::journal::Operation;
 use crate::memory::STATE;
+use crate::schema::Versioned;
+use crate::top_up::notify_pending_top_up;
+use crate::top_up::PendingTopUp;
+use crate::top_up::TopUpStatus;
 use crate::utils::transfer_tokens;
 use crate::Result;
 
@@ -16,17 +22,26 @@ use exchange::GetExchangeRateRequest;
 use exchange::GetExchangeRateResult;
 use ic_ledger_types::AccountIdentifier;
 use ic_ledger_types::BlockIndex;
-use ic_ledger_types::Memo;
 use ic_ledger_types::Subaccount;
+use ic_ledger_types::Timestamp;
 use ic_ledger_types::Tokens;
+use ic_ledger_types::DEFAULT_FEE;
 use serde::Deserialize;
 
 pub const MEMO_TOP_UP_CANISTER: u64 = 1347768404_u64;
 pub const MAINNET_CYCLE_MINTER_CANISTER_ID: Principal =
     Principal::from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x01, 0x01]);
+pub const MAINNET_EXCHANGE_RATE_CANISTER_ID: Principal =
+    Principal::from_slice(&[0x00, 0x00, 0x00, 0x00, 0x02, 0x10, 0x00, 0x01, 0x01, 0x01]);
 const NOTIFY_TOP_UP_METHOD: &str = "notify_top_up";
+const CYCLES_PER_XDR: u64 = 1_000_000_000_000;
+// Charging less than a transfer fee for cycles would cost the platform more than it charges.
+const MINIMUM_CYCLES_CHARGE: Tokens = DEFAULT_FEE;
+const RATE_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+// Quotes are not given from a rate older than this, whatever its expiry.
+pub const MAX_RATE_AGE: Duration = Duration::from_secs(60 * 60);
 
-async fn icp_cycles_exchange_rate() -> Result<u64> {
+async fn xrc_icp_cycles_exchange_rate() -> Result<(u64, u32)> {
     let request = GetExchangeRateRequest {
         quote_asset: Asset {
             symbol: "Cycles".to_string(),
@@ -39,8 +54,9 @@ async fn icp_cycles_exchange_rate() -> Result<u64> {
         timestamp: None,
     };
 
+    let exchange_rate_canister_id = STATE.with_borrow(|s| s.settings().exchange_rate_canister_id);
     let response = ic_cdk::api::call::call_with_payment::<_, (GetExchangeRateResult,)>(
-        exchange::CANISTER_ID,
+        exchange_rate_canister_id,
         "get_exchange_rate",
         (request,),
         1_000_000_000, // 1B cycles should be sent with each request.
@@ -55,44 +71,219 @@ async fn icp_cycles_exchange_rate() -> Result<u64> {
     .0;
 
     match response {
-        GetExchangeRateResult::Ok(r) => Ok(r.rate),
+        GetExchangeRateResult::Ok(r) => Ok((r.rate, r.metadata.decimals)),
         GetExchangeRateResult::Err(e) => Err(Error::Internal(format!(
             "Failed to fetch exchange rate, error: {e:?}"
         ))),
     }
 }
 
-/// Get exchange rate of ICP token to Cycles
-async fn get_and_update_icp_cycles_exchange_rate() -> Result<u64> {
-    async fn renew() -> Result<u64> {
-        let rate = crate::utils::exchange::icp_cycles_exchange_rate().await?;
-        STATE.with_borrow_mut(|s| {
-            s.icp_cycles_exchange_rate =
-                Some((rate, Instant::now() + s.settings().exchange_rate_timeout));
-        });
+#[derive(CandidType, Deserialize)]
+struct IcpXdrConversionRate {
+    xdr_permyriad_per_icp: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+struct IcpXdrConversionRateResponse {
+    data: IcpXdrConversionRate,
+}
+
+async fn cmc_icp_cycles_exchange_rate() -> Result<(u64, u32)> {
+    let cycles_minting_canister_id = STATE.with_borrow(|s| s.settings().cycles_minting_canister_id);
+    let response = ic_cdk::call::<_, (IcpXdrConversionRateResponse,)>(
+        cycles_minting_canister_id,
+        "get_icp_xdr_conversion_rate",
+        (),
+    )
+    .await
+    .map_err(|e| {
+        Error::Internal(format!(
+            "Failed to fetch conversion rate, error_code: {:?}, reason: {}",
+            e.0, e.1
+        ))
+    })?
+    .0;
+
+    // The cycles minting canister converts 1 XDR into 1 trillion cycles.
+    let rate = response
+        .data
+        .xdr_permyriad_per_icp
+        .checked_mul(CYCLES_PER_XDR / 10_000)
+        .ok_or_else(|| Error::Internal("Conversion rate is too large".to_string()))?;
+    Ok((rate, 0))
+}
+
+fn cycles_per_icp((rate, decimals): (u64, u32)) -> f64 {
+    rate as f64 / 10_f64.powi(decimals as i32)
+}
+
+// Relative difference of the exchange rate canister rate to the cycles minting canister one.
+fn rates_diverge(xrc: (u64, u32), cmc: (u64, u32), tolerance: f32) -> bool {
+    let (xrc, cmc) = (cycles_per_icp(xrc), cycles_per_icp(cmc));
+    (xrc - cmc).abs() > cmc * tolerance as f64
+}
+
+/// Exchange rate of ICP to cycles from the exchange rate canister, checked against the conversion
+/// rate of the cycles minting canister, which is used instead when the former is not available.
+async fn icp_cycles_exchange_rate() -> Result<(u64, u32)> {
+    let xrc = xrc_icp_cycles_exchange_rate().await;
+    let cmc = cmc_icp_cycles_exchange_rate().await;
+    match (xrc, cmc) {
+        (Ok(xrc), Ok(cmc)) => {
+            let tolerance = STATE.with_borrow(|s| s.settings().exchange_rate_tolerance);
+            if rates_diverge(xrc, cmc, tolerance) {
+                return Err(Error::ExchangeRatesDiverge {
+                    exchange_rate_canister: cycles_per_icp(xrc) as u64,
+                    cycles_minting_canister: cycles_per_icp(cmc) as u64,
+                });
+            }
+            Ok(xrc)
+        }
+        (Ok(rate), Err(_)) | (Err(_), Ok(rate)) => Ok(rate),
+        (Err(e), Err(_)) => Err(e),
+    }
+}
+
+/// Cached exchange rate of ICP to cycles.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct IcpCyclesRate {
+    // Cycles per ICP token, as a fixed-point number with `decimals` decimal places.
+    pub rate: u64,
+    pub decimals: u32,
+    pub fetched_at: Timestamp,
+    pub expires_at: Timestamp,
+}
+
+impl IcpCyclesRate {
+    fn is_expired(&self) -> bool {
+        ic_cdk::api::time() >= self.expires_at.timestamp_nanos || self.is_stale()
+    }
+
+    /// Whether the rate was fetched more than `MAX_RATE_AGE` ago.
+    pub fn is_stale(&self) -> bool {
+        ic_cdk::api::time().saturating_sub(self.fetched_at.timestamp_nanos)
+            > MAX_RATE_AGE.as_nanos() as u64
+    }
+
+    /// ICP tokens to convert into `cycles`, rounded up to the next e8 and never less than
+    /// `MINIMUM_CYCLES_CHARGE`.
+    pub fn icp_needed_for_cycles(&self, cycles: u64) -> Result<Tokens> {
+        if self.rate == 0 {
+            return Err(Error::Internal("Exchange rate is zero".to_string()));
+        }
+
+        // e8s = cycles * 10^decimals * 10^8 / rate
+        let e8s = 10_u128
+            .checked_pow(self.decimals)
+            .and_then(|scale| scale.checked_mul(Tokens::SUBDIVIDABLE_BY as u128))
+            .and_then(|scale| scale.checked_mul(cycles as u128))
+            .map(|scaled| scaled.div_ceil(self.rate as u128))
+            .and_then(|e8s| u64::try_from(e8s).ok())
+            .ok_or_else(|| {
+                Error::Internal(format!(
+                    "Failed to convert {cycles} cycles, rate: {}, decimals: {}",
+                    self.rate, self.decimals
+                ))
+            })?;
+        Ok(Tokens::from_e8s(e8s).max(MINIMUM_CYCLES_CHARGE))
+    }
+}
+
+impl Versioned for IcpCyclesRate {
+    const NAME: &'static str = "exchange rate";
+    const VERSION: u8 = 1;
+}
+
+async fn renew_icp_cycles_exchange_rate() -> Result<IcpCyclesRate> {
+    let (rate, decimals) = icp_cycles_exchange_rate().await?;
+    STATE.with_borrow_mut(|s| {
+        let timeout = s.settings().exchange_rate_timeout.as_nanos() as u64;
+        let now = ic_cdk::api::time();
+        let rate = IcpCyclesRate {
+            rate,
+            decimals,
+            fetched_at: Timestamp {
+                timestamp_nanos: now,
+            },
+            expires_at: Timestamp {
+                timestamp_nanos: now.saturating_add(timeout),
+            },
+        };
+        s.set_icp_cycles_rate(rate.clone());
         Ok(rate)
+    })
+}
+
+/// Get exchange rate of ICP token to Cycles
+async fn get_and_update_icp_cycles_exchange_rate() -> Result<IcpCyclesRate> {
+    match STATE.with_borrow(|s| s.icp_cycles_rate()) {
+        Some(rate) if !rate.is_expired() => Ok(rate),
+        _ => renew_icp_cycles_exchange_rate().await,
     }
+}
 
-    match STATE.with_borrow(|s| s.icp_cycles_exchange_rate) {
-        Some((_, timeout)) if Instant::now() > timeout => renew().await,
-        None => renew().await,
-        Some((rate, _)) => Ok(rate),
+/// Periodically renew the exchange rate before it expires, so charges rarely wait on the
+/// exchange rate canister.
+pub fn start_exchange_rate_refresh_timer() {
+    async fn refresh() {
+        let expires_soon = STATE.with_borrow(|s| {
+            s.icp_cycles_rate().map_or(true, |rate| {
+                let refresh_at =
+                    ic_cdk::api::time().saturating_add(RATE_REFRESH_INTERVAL.as_nanos() as u64);
+                rate.expires_at.timestamp_nanos <= refresh_at
+                    || rate.fetched_at.timestamp_nanos + MAX_RATE_AGE.as_nanos() as u64
+                        <= refresh_at
+            })
+        });
+        if expires_soon {
+            // Failures are retried on the next tick, or when the rate is needed.
+            let _ = renew_icp_cycles_exchange_rate().await;
+        }
     }
+
+    ic_cdk_timers::set_timer(Duration::ZERO, || ic_cdk::spawn(refresh()));
+    ic_cdk_timers::set_timer_interval(RATE_REFRESH_INTERVAL, || ic_cdk::spawn(refresh()));
+}
+
+/// ICP tokens to convert into `amount` cycles at the current exchange rate.
+pub async fn icp_needed_for_cycles(amount: u64) -> Result<Tokens> {
+    get_and_update_icp_cycles_exchange_rate()
+        .await?
+        .icp_needed_for_cycles(amount)
 }
 
 pub async fn top_up_canister(
+    developer_id: DeveloperID,
     from: Subaccount,
-    app_id: AppID,
+    app_id: Option<AppID>,
     amount: u64,
 ) -> Result<(u128, Tokens)> {
-    let rate = get_and_update_icp_cycles_exchange_rate().await?;
-    let icp_needed = Tokens::from_e8s((amount / rate) * Tokens::SUBDIVIDABLE_BY);
+    let icp_needed = icp_needed_for_cycles(amount).await?;
+
+    // The cycles minting canister recognizes top-ups by their memo.
+    let operation = Operation::with_memo(MEMO_TOP_UP_CANISTER);
+    let canister_id = app_id.unwrap_or_else(ic_cdk::id);
+    let cycles_minting_canister_id = STATE.with_borrow(|s| s.settings().cycles_minting_canister_id);
+    let to = AccountIdentifier::new(&cycles_minting_canister_id, &Subaccount::from(canister_id));
 
-    let memo = Memo(MEMO_TOP_UP_CANISTER);
-    let to = AccountIdentifier::new(&MAINNET_CYCLE_MINTER_CANISTER_ID, &Subaccount::from(app_id));
+    let block_index = transfer_tokens(from, to, icp_needed, operation).await?;
 
-    let block_index = transfer_tokens(from, to, icp_needed, memo).await?;
-    let cycles = notify_top_up(app_id, block_index).await?;
+    // The tokens are already paid, so the top-up is recorded before notifying. If notifying
+    // fails, it is retried later, see `crate::top_up`.
+    let top_up = PendingTopUp {
+        block_index,
+        developer_id,
+        app_id,
+        amount: icp_needed,
+        created_at: Timestamp {
+            timestamp_nanos: ic_cdk::api::time(),
+        },
+        attempts: 0,
+        status: TopUpStatus::Pending,
+    };
+    STATE.with_borrow_mut(|s| s.insert_pending_top_up(top_up.clone()));
+    let cycles = notify_pending_top_up(&top_up).await?;
+    STATE.with_borrow_mut(|s| s.remove_pending_top_up(block_index));
     Ok((cycles, icp_needed))
 }
 
@@ -102,10 +293,10 @@ struct NotifyTopUpArg {
     pub canister_id: Principal,
 }
 
-type NotifyTopUpResult = std::result::Result<u128, NotifyError>;
+pub type NotifyTopUpResult = std::result::Result<u128, NotifyError>;
 
 #[derive(CandidType, Deserialize, Debug)]
-enum NotifyError {
+pub enum NotifyError {
     Refunded {
         reason: String,
         block_index: Option<BlockIndex>,
@@ -119,14 +310,20 @@ enum NotifyError {
     },
 }
 
-async fn notify_top_up(canister_id: Principal, block_index: BlockIndex) -> Result<u128> {
+/// Notify the cycles minting canister of a top-up, only failing to send the message is an error
+/// here as the caller decides what to do with the top-up.
+pub async fn notify_top_up(
+    canister_id: Principal,
+    block_index: BlockIndex,
+) -> Result<NotifyTopUpResult> {
     let args = NotifyTopUpArg {
         block_index,
         canister_id,
     };
 
-    ic_cdk::call::<_, (NotifyTopUpResult,)>(
-        MAINNET_CYCLE_MINTER_CANISTER_ID,
+    let cycles_minting_canister_id = STATE.with_borrow(|s| s.settings().cycles_minting_canister_id);
+    Ok(ic_cdk::call::<_, (NotifyTopUpResult,)>(
+        cycles_minting_canister_id,
         NOTIFY_TOP_UP_METHOD,
         (args,),
     )
@@ -137,6 +334,108 @@ async fn notify_top_up(canister_id: Principal, block_index: BlockIndex) -> Resul
             e.0, e.1
         ))
     })?
-    .0
-    .map_err(|e| Error::Internal(format!("Notify top-up failed, error_code: {e:?}",)))
+    .0)
+}
+
+#[ic_cdk::query]
+fn get_icp_cycles_rate() -> Option<crate::utils::exchange::IcpCyclesRate> {
+    STATE.with_borrow(|s| s.icp_cycles_rate())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRILLION: u64 = 1_000_000_000_000;
+
+    fn rate(rate: u64, decimals: u32) -> IcpCyclesRate {
+        IcpCyclesRate {
+            rate,
+            decimals,
+            fetched_at: Timestamp { timestamp_nanos: 0 },
+            expires_at: Timestamp { timestamp_nanos: 0 },
+        }
+    }
+
+    #[test]
+    fn converts_whole_tokens_exactly() {
+        let rate = rate(4 * TRILLION, 0);
+        assert_eq!(
+            Tokens::from_e8s(100_000_000),
+            rate.icp_needed_for_cycles(4 * TRILLION).unwrap()
+        );
+        assert_eq!(
+            Tokens::from_e8s(250_000_000),
+            rate.icp_needed_for_cycles(10 * TRILLION).unwrap()
+        );
+    }
+
+    #[test]
+    fn uses_the_rate_decimals() {
+        // 4 trillion cycles per ICP, with 4 decimal places.
+        let rate = rate(4 * TRILLION * 10_000, 4);
+        assert_eq!(
+            Tokens::from_e8s(100_000_000),
+            rate.icp_needed_for_cycles(4 * TRILLION).unwrap()
+        );
+
+        // 12.345 cycles per ICP, 12 cycles are 0.972053462... ICP.
+        let rate = self::rate(12_345, 3);
+        assert_eq!(
+            Tokens::from_e8s(97_205_347),
+            rate.icp_needed_for_cycles(12).unwrap()
+        );
+    }
+
+    #[test]
+    fn rounds_up_to_the_next_e8() {
+        let rate = rate(4 * TRILLION, 0);
+        assert_eq!(
+            Tokens::from_e8s(100_000_001),
+            rate.icp_needed_for_cycles(4 * TRILLION + 1).unwrap()
+        );
+        // One e8 buys 40_000 cycles.
+        assert_eq!(
+            Tokens::from_e8s(10_001),
+            rate.icp_needed_for_cycles(10_000 * 40_000 + 1).unwrap()
+        );
+    }
+
+    #[test]
+    fn charges_at_least_the_minimum() {
+        let rate = rate(4 * TRILLION, 0);
+        assert_eq!(
+            MINIMUM_CYCLES_CHARGE,
+            rate.icp_needed_for_cycles(0).unwrap()
+        );
+        assert_eq!(
+            MINIMUM_CYCLES_CHARGE,
+            rate.icp_needed_for_cycles(1).unwrap()
+        );
+        assert_eq!(
+            MINIMUM_CYCLES_CHARGE,
+            rate.icp_needed_for_cycles(9_999 * 40_000).unwrap()
+        );
+    }
+
+    #[test]
+    fn compares_rates_with_the_tolerance() {
+        // 4 trillion cycles per ICP from both sources, with different decimals.
+        let cmc = (4 * TRILLION, 0);
+        assert!(!rates_diverge((4 * TRILLION * 1_000, 3), cmc, 0.05));
+        assert!(!rates_diverge((4_200 * TRILLION, 3), cmc, 0.05));
+        assert!(!rates_diverge((3_800 * TRILLION, 3), cmc, 0.05));
+        assert!(rates_diverge((4_201 * TRILLION, 3), cmc, 0.05));
+        assert!(rates_diverge((3_799 * TRILLION, 3), cmc, 0.05));
+        assert!(rates_diverge((3_799 * TRILLION, 3), cmc, 0.01));
+    }
+
+    #[test]
+    fn rejects_invalid_rates() {
+        assert!(rate(0, 0).icp_needed_for_cycles(TRILLION).is_err());
+        // 10^39 does not fit in 128 bits.
+        assert!(rate(4 * TRILLION, 39).icp_needed_for_cycles(1).is_err());
+        // More than `u64::MAX` e8s.
+        assert!(rate(1, 0).icp_needed_for_cycles(u64::MAX).is_err());
+    }
 }
@@ -0,0 +1,115 @@
+use candid::Principal;
+use ic_cdk::api::management_canister::main::canister_status;
+use ic_cdk::api::management_canister::main::clear_chunk_store;
+use ic_cdk::api::management_canister::main::create_canister;
+use ic_cdk::api::management_canister::main::install_chunked_code;
+use ic_cdk::api::management_canister::main::install_code;
+use ic_cdk::api::management_canister::main::upload_chunk;
+use ic_cdk::api::management_canister::main::CanisterIdRecord;
+use ic_cdk::api::management_canister::main::CanisterInstallMode;
+use ic_cdk::api::management_canister::main::CanisterSettings;
+use ic_cdk::api::management_canister::main::ClearChunkStoreArgument;
+use ic_cdk::api::management_canister::main::CreateCanisterArgument;
+use ic_cdk::api::management_canister::main::InstallChunkedCodeArgument;
+use ic_cdk::api::management_canister::main::InstallCodeArgument;
+use ic_cdk::api::management_canister::main::UploadChunkArgument;
+use sha2::Digest;
+use sha2::Sha256;
+
+use crate::error::Error;
+use crate::Result;
+
+/// Create a new canister controlled by this canister, paying `cycles` for it.
+pub async fn create_app_canister(cycles: u128) -> Result<Principal> {
+    let args = CreateCanisterArgument {
+        settings: Some(CanisterSettings {
+            controllers: Some(vec![ic_cdk::id()]),
+            ..Default::default()
+        }),
+    };
+
+    create_canister(args, cycles)
+        .await
+        .map(|(r,)| r.canister_id)
+        .map_err(|e| {
+            Error::Internal(format!(
+                "Failed to create canister, error_code: {:?}, reason: {}",
+                e.0, e.1
+            ))
+        })
+}
+
+/// Whether a wasm module is installed on the canister, which this canister controls.
+pub async fn has_app_code(canister_id: Principal) -> Result<bool> {
+    canister_status(CanisterIdRecord { canister_id })
+        .await
+        .map(|(status,)| status.module_hash.is_some())
+        .map_err(|e| {
+            Error::Internal(format!(
+                "Failed to get canister status, error_code: {:?}, reason: {}",
+                e.0, e.1
+            ))
+        })
+}
+
+// Maximum size of a chunk in a canister chunk store.
+const WASM_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Install `wasm_module` on the canister, modules bigger than a single message are uploaded to
+/// the chunk store of the canister first.
+pub async fn install_app_code(
+    canister_id: Principal,
+    wasm_module: Vec<u8>,
+    mode: CanisterInstallMode,
+) -> Result<()> {
+    if wasm_module.len() <= WASM_CHUNK_SIZE {
+        let args = InstallCodeArgument {
+            mode,
+            canister_id,
+            wasm_module,
+            arg: Vec::new(),
+        };
+
+        return install_code(args).await.map_err(|e| {
+            Error::Internal(format!(
+                "Failed to install code, error_code: {:?}, reason: {}",
+                e.0, e.1
+            ))
+        });
+    }
+
+    let mut chunk_hashes_list = Vec::new();
+    for chunk in wasm_module.chunks(WASM_CHUNK_SIZE) {
+        let args = UploadChunkArgument {
+            canister_id,
+            chunk: chunk.to_vec(),
+        };
+        let (hash,) = upload_chunk(args).await.map_err(|e| {
+            Error::Internal(format!(
+                "Failed to upload code chunk, error_code: {:?}, reason: {}",
+                e.0, e.1
+            ))
+        })?;
+        chunk_hashes_list.push(hash);
+    }
+
+    let args = InstallChunkedCodeArgument {
+        mode,
+        target_canister: canister_id,
+        store_canister: None,
+        chunk_hashes_list,
+        wasm_module_hash: Sha256::digest(&wasm_module).to_vec(),
+        arg: Vec::new(),
+    };
+    let result = install_chunked_code(args).await.map_err(|e| {
+        Error::Internal(format!(
+            "Failed to install chunked code, error_code: {:?}, reason: {}",
+            e.0, e.1
+        ))
+    });
+
+    // The chunks are no longer needed and count towards the canister memory usage.
+    let _ = clear_chunk_store(ClearChunkStoreArgument { canister_id }).await;
+
+    result
+}